@@ -1,14 +1,57 @@
 use std::{fmt::Debug, sync::LazyLock};
 
-use crate::{Result, fs::*, util::*};
+use crate::{Result, fs::*, process::DynInteractor, util::*};
 use e4pty::prelude::*;
 
 pub struct Output {
+    /// the exit code, `128 + n` if killed by signal `n` like shells do
     pub code: i32,
+    /// the name of the signal without `SIG` if the process was killed, e.g. `KILL`
+    pub signal: Option<String>,
     pub stdout: Vec<u8>,
     pub stderr: Vec<u8>,
 }
 
+/// Names of the common signals, as used by `exit-signal` of SSH.
+const SIGNALS: &[(i32, &str)] = &[
+    (1, "HUP"),
+    (2, "INT"),
+    (3, "QUIT"),
+    (4, "ILL"),
+    (6, "ABRT"),
+    (8, "FPE"),
+    (9, "KILL"),
+    (10, "USR1"),
+    (11, "SEGV"),
+    (12, "USR2"),
+    (13, "PIPE"),
+    (14, "ALRM"),
+    (15, "TERM"),
+];
+
+impl Output {
+    /// Whether the process exited by itself, rather than being killed by a signal.
+    pub fn exited(&self) -> bool {
+        self.signal.is_none()
+    }
+    pub fn signal_name(number: i32) -> String {
+        SIGNALS
+            .iter()
+            .find(|(n, _)| *n == number)
+            .map(|(_, name)| name.to_string())
+            .unwrap_or_else(|| number.to_string())
+    }
+    /// The exit code for a process killed by the named signal.
+    pub fn signal_code(name: &str) -> i32 {
+        SIGNALS
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(number, _)| *number)
+            .or_else(|| name.parse().ok())
+            .map_or(1, |number| 128 + number)
+    }
+}
+
 pub static VARIABLE_RE: LazyLock<regex::Regex> =
     LazyLock::new(|| regex::Regex::new(r"\$\{([^}]+)\}").expect("invalid regex"));
 
@@ -17,12 +60,27 @@ pub trait UserImpl {
     //FIX:about path encoding, should I use Utf8Path?
     //TODO:better path handling
     async fn file_attributes(&self, path: &XPath) -> (XPathBuf, Result<FileAttributes>);
-    async fn glob_file_meta(&self, path: &XPath) -> Result<Vec<Metadata>>;
+    /// List the regular files under `path`, the ignored ones are skipped without descending.
+    async fn glob_file_meta(&self, path: &XPath, ignore: &Ignore) -> Result<Vec<Metadata>>;
     async fn copy(&self, src_path: &str, dst: &str, dst_path: &str) -> Result<()>;
     async fn open(&self, path: &str, flags: OpenFlags, attr: FileAttributes) -> Result<BoxedFile>;
+    /// Remove a regular file.
+    async fn remove(&self, path: &str) -> Result<()>;
     async fn auto(&self, name: &str, action: &str, args: Option<&str>) -> Result<()>;
-    async fn exec(&self, command: Script<'_, '_>) -> Result<Output>;
-    async fn pty(&self, command: Script<'_, '_>, win_size: WindowSize) -> Result<BoxedPty>;
+    async fn exec(&self, command: Script<'_, '_>, opts: &ExecOptions) -> Result<Output>;
+    /// Like `exec`, but forward the lines of stdout and stderr to `interactor` as they arrive.
+    async fn exec_stream(
+        &self,
+        command: Script<'_, '_>,
+        opts: &ExecOptions,
+        interactor: &DynInteractor,
+    ) -> Result<Output>;
+    async fn pty(
+        &self,
+        command: Script<'_, '_>,
+        win_size: WindowSize,
+        opts: &ExecOptions,
+    ) -> Result<BoxedPty>;
 }
 
 pub type BoxedUser = Box<dyn UserImpl + Send + Sync>;
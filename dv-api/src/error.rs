@@ -13,6 +13,10 @@ pub enum Error {
     SFTP(#[from] russh_sftp::client::error::Error),
     #[error("ssh key error: {0}")]
     SSHKey(#[from] russh::keys::Error),
+    #[error("host key of {host} changed, see known_hosts line {line}")]
+    HostKeyChanged { host: String, line: usize },
+    #[error("host key of {0} is not trusted")]
+    HostKeyUntrusted(String),
     #[error("io error: {0}")]
     IO(#[from] std::io::Error),
     #[error("pty error: {0}")]
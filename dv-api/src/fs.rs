@@ -1,7 +1,12 @@
-use crate::util::*;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::{Result, util::*, whatever};
 
 pub use russh_sftp::protocol::FileAttributes;
 
+/// The file in a directory listing the patterns to skip when walking it.
+pub const IGNORE_FILE: &str = ".dvignore";
+
 #[derive(Debug, Clone)]
 pub struct Metadata {
     pub path: XPathBuf,
@@ -12,6 +17,98 @@ pub struct Metadata {
 pub struct DirInfo {
     pub path: XPathBuf,
     pub files: Vec<Metadata>,
+    /// the patterns the files were listed with
+    pub ignore: Ignore,
+}
+
+/// Gitignore-style patterns matched against paths relative to the walked directory,
+/// a `!pattern` includes again what an earlier one excluded.
+#[derive(Debug, Clone)]
+pub struct Ignore {
+    matcher: Gitignore,
+    patterns: Vec<String>,
+}
+
+impl Default for Ignore {
+    fn default() -> Self {
+        Self {
+            matcher: Gitignore::empty(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+/// A directory a walk may skip without listing what is in it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Prune<'a> {
+    /// relative to the walked directory
+    Path(&'a str),
+    /// at any depth
+    Name(&'a str),
+}
+
+impl Ignore {
+    pub fn new<'a>(patterns: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new("");
+        let mut lines = Vec::new();
+        for pattern in patterns {
+            if let Err(e) = builder.add_line(None, pattern) {
+                whatever!("invalid ignore pattern {}: {}", pattern, e)
+            }
+            lines.push(pattern.to_string());
+        }
+        match builder.build() {
+            Ok(matcher) => Ok(Self {
+                matcher,
+                patterns: lines,
+            }),
+            Err(e) => whatever!("build ignore patterns failed: {}", e),
+        }
+    }
+    /// Whether `path` itself is ignored, for walks that skip the ignored directories.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        self.matcher.matched(path, is_dir).is_ignore()
+    }
+    /// Whether `path` or any directory it is in is ignored.
+    pub fn is_ignored_in(&self, path: &str) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(path, false)
+            .is_ignore()
+    }
+    /// The directories of the plain patterns, which are ignored as a whole. Patterns with
+    /// wildcards, and those a negation may include again, are only matched after listing.
+    pub fn prunable(&self) -> Vec<Prune<'_>> {
+        let negated: Vec<_> = self
+            .patterns
+            .iter()
+            .filter_map(|p| p.trim().strip_prefix('!'))
+            .collect();
+        self.patterns
+            .iter()
+            .filter_map(|pattern| {
+                let pattern = pattern.trim();
+                if pattern.starts_with(['#', '!']) || pattern.contains(['*', '?', '[', '\\']) {
+                    return None;
+                }
+                let dir = pattern.trim_end_matches('/');
+                let path = dir.strip_prefix('/').unwrap_or(dir);
+                if path
+                    .split('/')
+                    .any(|c| c.is_empty() || c == "." || c == "..")
+                    || negated.iter().any(|n| n.contains(path))
+                    || !self.is_ignored(path, true)
+                {
+                    return None;
+                }
+                //NOTE:like gitignore, a slash other than a trailing one anchors the pattern
+                Some(if path.len() != dir.len() || path.contains('/') {
+                    Prune::Path(path)
+                } else {
+                    Prune::Name(path)
+                })
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -37,3 +134,33 @@ bitflags::bitflags! {
 pub trait FileImpl: AsyncStream {}
 
 pub type BoxedFile = Box<dyn FileImpl + Unpin + Send>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prunable_patterns() {
+        let ignore = Ignore::new([
+            "# comment",
+            "build/",
+            ".git",
+            "/target",
+            "docs/gen/",
+            "*.swp",
+            "cache/",
+            "!cache/keep",
+            "",
+        ])
+        .unwrap();
+        assert_eq!(
+            ignore.prunable(),
+            [
+                Prune::Name("build"),
+                Prune::Name(".git"),
+                Prune::Path("target"),
+                Prune::Path("docs/gen"),
+            ]
+        );
+    }
+}
@@ -13,6 +13,11 @@ pub trait Interactor {
     /// - `msg`: The message to display to the user.
     /// - `opts`: The options to display to the user. For example, `["y/exec", "n/do nothing"]`.
     async fn confirm(&self, msg: String, opts: &[&str]) -> crate::Result<usize>;
+    /// Read a line of input from the user.
+    /// # Parameters
+    /// - `msg`: The message to display to the user.
+    /// - `echo`: Whether to show the input, should be `false` for passwords and passphrases.
+    async fn prompt(&self, msg: String, echo: bool) -> crate::Result<String>;
 }
 
 pub type DynInteractor = dyn Interactor + Sync;
@@ -34,3 +39,105 @@ impl<T: Future<Output = Result<Output>> + Send> PtyProcessConsumer for T {
         Ok(String::from_utf8_lossy(&stdout).to_string())
     }
 }
+
+/// Collects one output stream of a process, forwarding complete lines to the interactor if any.
+pub(crate) struct OutputSink<'a> {
+    buf: Vec<u8>,
+    /// the start of the line not forwarded yet
+    pos: usize,
+    interactor: Option<&'a DynInteractor>,
+}
+
+impl<'a> OutputSink<'a> {
+    pub fn new(interactor: Option<&'a DynInteractor>) -> Self {
+        Self {
+            buf: Vec::new(),
+            pos: 0,
+            interactor,
+        }
+    }
+    pub async fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+        let Some(interactor) = self.interactor else {
+            return;
+        };
+        while let Some(i) = self.buf[self.pos..].iter().position(|&b| b == b'\n') {
+            let line = &self.buf[self.pos..self.pos + i];
+            interactor
+                .log(
+                    String::from_utf8_lossy(line)
+                        .trim_end_matches('\r')
+                        .to_string(),
+                )
+                .await;
+            self.pos += i + 1;
+        }
+    }
+    /// Forward the last line even without a newline, and return all the output.
+    pub async fn finish(self) -> Vec<u8> {
+        if let Some(interactor) = self.interactor
+            && self.pos < self.buf.len()
+        {
+            let line = String::from_utf8_lossy(&self.buf[self.pos..]).to_string();
+            interactor.log(line).await;
+        }
+        self.buf
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use super::*;
+    use crate::whatever;
+
+    /// Answers prompts and confirmations from a script, recording what it was asked and told.
+    #[derive(Default)]
+    pub struct Scripted {
+        answers: Mutex<VecDeque<String>>,
+        /// the prompts asked, with whether the input is echoed
+        pub prompts: Mutex<Vec<(String, bool)>>,
+        pub logs: Mutex<Vec<String>>,
+    }
+
+    impl Scripted {
+        pub fn new(answers: &[&str]) -> Self {
+            Self {
+                answers: Mutex::new(answers.iter().map(|a| a.to_string()).collect()),
+                ..Default::default()
+            }
+        }
+        fn next(&self, msg: &str) -> Result<String> {
+            match self.answers.lock().unwrap().pop_front() {
+                Some(answer) => Ok(answer),
+                None => whatever!("no answer scripted for {}", msg),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Interactor for Scripted {
+        async fn window_size(&self) -> WindowSize {
+            WindowSize { rows: 24, cols: 80 }
+        }
+        async fn log(&self, msg: String) {
+            self.logs.lock().unwrap().push(msg);
+        }
+        async fn ask(&self, _: BoxedPty) -> Result<i32> {
+            whatever!("no terminal in tests")
+        }
+        /// The answer is the first letter of the option to choose.
+        async fn confirm(&self, msg: String, opts: &[&str]) -> Result<usize> {
+            let answer = self.next(&msg)?;
+            match opts.iter().position(|opt| opt.starts_with(answer.as_str())) {
+                Some(i) => Ok(i),
+                None => whatever!("{} is not one of {:?}", answer, opts),
+            }
+        }
+        async fn prompt(&self, msg: String, echo: bool) -> Result<String> {
+            self.prompts.lock().unwrap().push((msg.clone(), echo));
+            self.next(&msg)
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use resplus::attach;
 use std::{borrow::Cow, collections::HashMap, sync::Arc};
 
+use tokio::io::AsyncReadExt;
 use tracing::debug;
 
 mod dev {
@@ -18,6 +19,8 @@ pub use config::Config;
 use crate::{process::DynInteractor, whatever};
 mod device;
 pub use device::Dev;
+mod keyring;
+pub use keyring::Keyring;
 mod multi;
 
 #[derive(Debug)]
@@ -84,31 +87,79 @@ impl User {
         }
     }
     pub async fn check_path<'a, 'b: 'a>(&'b self, path: &'a str) -> Result<CheckInfo> {
+        self.check_path_with(path, &[]).await
+    }
+    /// Like `check_path`, a directory is listed without the files matched by `patterns`
+    /// or by the `.dvignore` in it.
+    pub async fn check_path_with<'a, 'b: 'a>(
+        &'b self,
+        path: &'a str,
+        patterns: &[String],
+    ) -> Result<CheckInfo> {
         let path = self.normalize(path);
         let (path, fa) = self.inner.file_attributes(&path).await;
         debug!("check_path:{}", path);
         let attr = fa?;
         let info = if attr.is_dir() {
-            let files = self.inner.glob_file_meta(&path).await?;
-            CheckInfo::Dir(DirInfo { path, files })
+            let ignore = self.load_ignore(&path, patterns).await?;
+            let files = self.inner.glob_file_meta(&path, &ignore).await?;
+            CheckInfo::Dir(DirInfo {
+                path,
+                files,
+                ignore,
+            })
         } else {
             CheckInfo::File(Metadata { path, attr })
         };
         Ok(info)
     }
     pub async fn check_dir(&self, path: &str) -> Result<DirInfo> {
+        self.check_dir_with(path, &[]).await
+    }
+    /// Like `check_dir`, without the files matched by `patterns` or by the `.dvignore` in it.
+    pub async fn check_dir_with(&self, path: &str, patterns: &[String]) -> Result<DirInfo> {
         let path = self.normalize(path);
         let (path, fa) = self.inner.file_attributes(&path).await;
         let fa = fa?;
         if !fa.is_dir() {
             whatever!("{} not a directory", path);
         }
-        let metadata = self.inner.glob_file_meta(&path).await?;
+        let ignore = self.load_ignore(&path, patterns).await?;
+        self.list_dir(path, ignore).await
+    }
+    /// List a directory with the patterns of another one, e.g. the source of a copy.
+    pub async fn list_dir(&self, path: XPathBuf, ignore: Ignore) -> Result<DirInfo> {
+        let files = self.inner.glob_file_meta(&path, &ignore).await?;
         Ok(DirInfo {
             path,
-            files: metadata,
+            files,
+            ignore,
         })
     }
+    /// `patterns` come after the lines of `.dvignore`, so they can include again what it
+    /// excludes. The `.dvignore` itself is always ignored.
+    async fn load_ignore(&self, dir: &XPath, patterns: &[String]) -> Result<Ignore> {
+        let mut path = dir.to_owned();
+        path.push(IGNORE_FILE);
+        let mut content = String::new();
+        match self
+            .inner
+            .open(path.as_str(), OpenFlags::READ, FileAttributes::default())
+            .await
+        {
+            Ok(mut file) => {
+                file.read_to_string(&mut content).await?;
+            }
+            Err(e) if e.is_not_found() => {}
+            Err(e) => return Err(e),
+        }
+        let own = format!("/{}", IGNORE_FILE);
+        Ignore::new(
+            std::iter::once(own.as_str())
+                .chain(content.lines())
+                .chain(patterns.iter().map(|p| p.as_str())),
+        )
+    }
     pub async fn copy(&self, src_path: &XPath, dst: &str, dst_path: &XPath) -> Result<()> {
         let src_path = self.normalize(src_path);
         let dst_path = self.normalize(dst_path);
@@ -120,6 +171,10 @@ impl User {
         .await?;
         Ok(())
     }
+    pub async fn remove(&self, path: &XPath) -> Result<()> {
+        let path = self.normalize(path);
+        attach!(self.inner.remove(path.as_str()), 0).await
+    }
     pub async fn auto(&self, name: &str, action: &str, args: Option<&str>) -> Result<()> {
         self.inner.auto(name, action, args).await
     }
@@ -127,10 +182,29 @@ impl User {
         packages.install(self, interactor, &self.dev.pm).await
     }
     pub async fn pty(&self, s: Script<'_, '_>, win_size: WindowSize) -> Result<BoxedPty> {
-        self.inner.pty(s, win_size).await
+        self.inner.pty(s, win_size, &ExecOptions::default()).await
+    }
+    pub async fn pty_with(
+        &self,
+        s: Script<'_, '_>,
+        win_size: WindowSize,
+        opts: &ExecOptions,
+    ) -> Result<BoxedPty> {
+        self.inner.pty(s, win_size, opts).await
     }
     pub async fn exec(&self, s: Script<'_, '_>) -> Result<Output> {
-        self.inner.exec(s).await
+        self.inner.exec(s, &ExecOptions::default()).await
+    }
+    pub async fn exec_with(&self, s: Script<'_, '_>, opts: &ExecOptions) -> Result<Output> {
+        self.inner.exec(s, opts).await
+    }
+    pub async fn exec_stream(
+        &self,
+        s: Script<'_, '_>,
+        opts: &ExecOptions,
+        interactor: &DynInteractor,
+    ) -> Result<Output> {
+        self.inner.exec_stream(s, opts, interactor).await
     }
     pub async fn open(&self, path: &XPath, opt: OpenFlags) -> Result<BoxedFile> {
         self.open_with_attr(path, opt, FileAttributes::default())
@@ -146,3 +220,27 @@ impl User {
         attach!(self.inner.open(path.as_str(), flags, attr), 0).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::tests::Scripted;
+
+    #[tokio::test]
+    async fn missing_ignore_file() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("f0"), "f0").unwrap();
+        let user = Config::default()
+            .connect(None, &Scripted::new(&[]), &Arc::<Keyring>::default())
+            .await
+            .unwrap();
+        let missing = dir.path().join("sub").join(IGNORE_FILE);
+        let res = user
+            .open(missing.to_str().unwrap().into(), OpenFlags::READ)
+            .await;
+        assert!(res.unwrap_err().is_not_found());
+        assert!(!dir.path().join("sub").exists());
+        let info = user.check_dir(dir.path().to_str().unwrap()).await.unwrap();
+        assert_eq!(info.files.len(), 1);
+    }
+}
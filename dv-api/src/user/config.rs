@@ -4,7 +4,7 @@ use std::{
 };
 
 #[cfg_attr(feature = "rune", derive(rune::Any))]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Config {
     #[cfg_attr(feature = "rune", rune(get, set))]
     pub is_system: Option<bool>,
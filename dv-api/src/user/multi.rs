@@ -1,6 +1,8 @@
 mod local;
 use std::sync::Arc;
 
+use crate::{process::DynInteractor, user::Keyring};
+
 mod ssh;
 
 use dev::{BoxedUser, into_boxed_user};
@@ -13,9 +15,14 @@ mod dev {
 }
 
 impl dev::Config {
-    pub async fn connect(mut self, dev: Option<Arc<dev::Dev>>) -> dev::Result<dev::User> {
+    pub async fn connect(
+        mut self,
+        dev: Option<Arc<dev::Dev>>,
+        interactor: &DynInteractor,
+        keyring: &Arc<Keyring>,
+    ) -> dev::Result<dev::User> {
         if let Some(host) = self.remove("HOST") {
-            ssh::create(host, self, dev).await
+            ssh::create(host, self, dev, interactor, keyring).await
         } else {
             local::create(self, dev).await
         }
@@ -1,4 +1,8 @@
-use crate::{Error, whatever};
+use crate::{
+    Error,
+    process::{DynInteractor, OutputSink},
+    whatever,
+};
 
 use super::dev::{self, *};
 use autox::AutoX;
@@ -6,7 +10,9 @@ use autox::AutoX;
 use std::{
     borrow::Cow,
     path::{Path, PathBuf},
+    process::Stdio,
 };
+use tokio::io::AsyncReadExt;
 use tracing::{debug, trace};
 
 mod config;
@@ -26,6 +32,65 @@ impl This {
             autox,
         })
     }
+    async fn run(
+        &self,
+        script: Script<'_, '_>,
+        opts: &ExecOptions,
+        interactor: Option<&DynInteractor>,
+    ) -> Result<Output> {
+        //NOTE:the script is removed when this returns, the child is killed by then
+        let (mut builder, _script) = script.into_command()?;
+        opts.apply(&mut builder);
+        let mut builder = tokio::process::Command::from(builder);
+        builder
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        //NOTE:a timed out child is killed with everything it spawned, so it gets its own group
+        #[cfg(not(windows))]
+        if opts.timeout.is_some() {
+            builder.process_group(0);
+        }
+        let mut child = builder.spawn()?;
+        let (Some(mut out), Some(mut err)) = (child.stdout.take(), child.stderr.take()) else {
+            whatever!("spawn without stdio")
+        };
+        let (mut stdout, mut stderr) = (OutputSink::new(interactor), OutputSink::new(interactor));
+        let (mut out_buf, mut err_buf) = ([0u8; 4096], [0u8; 4096]);
+        let (mut out_done, mut err_done) = (false, false);
+        let collect = async {
+            while !(out_done && err_done) {
+                tokio::select! {
+                    n = out.read(&mut out_buf), if !out_done => match n? {
+                        0 => out_done = true,
+                        n => stdout.push(&out_buf[..n]).await,
+                    },
+                    n = err.read(&mut err_buf), if !err_done => match n? {
+                        0 => err_done = true,
+                        n => stderr.push(&err_buf[..n]).await,
+                    },
+                }
+            }
+            child.wait().await
+        };
+        let status = match opts.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, collect).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    kill_tree(&mut child).await?;
+                    whatever!("timed out after {:?}", timeout)
+                }
+            },
+            None => collect.await?,
+        };
+        Ok(Output {
+            code: exit_status2exit_code(status),
+            signal: exit_status2signal(status),
+            stdout: stdout.finish().await,
+            stderr: stderr.finish().await,
+        })
+    }
     fn canonicalize<'a, 'b: 'a>(&'b self, path: &'a str) -> Result<Cow<'a, Path>> {
         let mut new = String::with_capacity(path.len());
         let mut last_match = 0;
@@ -82,12 +147,18 @@ impl UserImpl for This {
                 .map_err(|e| e.into()),
         )
     }
-    async fn glob_file_meta(&self, path2: &XPath) -> Result<Vec<Metadata>> {
+    async fn glob_file_meta(&self, path2: &XPath, ignore: &Ignore) -> Result<Vec<Metadata>> {
         let metadata = path2.metadata()?;
         if metadata.is_dir() {
             let mut result = Vec::new();
             for entry in walkdir::WalkDir::new(path2)
                 .into_iter()
+                .filter_entry(|e| {
+                    e.depth() == 0
+                        || e.path().strip_prefix(path2).is_ok_and(|rel| {
+                            !ignore.is_ignored(&rel.to_string_lossy(), e.path().is_dir())
+                        })
+                })
                 .filter_map(|e| e.ok())
             {
                 let file_path = entry.path();
@@ -127,6 +198,11 @@ impl UserImpl for This {
         std::fs::copy(&src2, &dst2)?;
         Ok(())
     }
+    async fn remove(&self, path: &str) -> Result<()> {
+        let path2 = self.canonicalize(path)?;
+        tokio::fs::remove_file(path2).await?;
+        Ok(())
+    }
     async fn auto(&self, name: &str, action: &str, args: Option<&str>) -> Result<()> {
         match (action, args) {
             ("setup", Some(args)) => self.autox.setup(name, args).await.map_err(Error::unknown)?,
@@ -136,22 +212,25 @@ impl UserImpl for This {
         };
         Ok(())
     }
-    async fn exec(&self, script: Script<'_, '_>) -> Result<Output> {
-        let mut builder = script.into_command()?;
-        builder
-            .stdin(std::process::Stdio::null())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped());
-        let output = builder.output()?;
-        Ok(Output {
-            code: exit_status2exit_code(output.status),
-            stdout: output.stdout,
-            stderr: output.stderr,
-        })
+    async fn exec(&self, script: Script<'_, '_>, opts: &ExecOptions) -> Result<Output> {
+        self.run(script, opts, None).await
     }
-    async fn pty(&self, command: Script<'_, '_>, win_size: WindowSize) -> Result<BoxedPty> {
+    async fn exec_stream(
+        &self,
+        script: Script<'_, '_>,
+        opts: &ExecOptions,
+        interactor: &DynInteractor,
+    ) -> Result<Output> {
+        self.run(script, opts, Some(interactor)).await
+    }
+    async fn pty(
+        &self,
+        command: Script<'_, '_>,
+        win_size: WindowSize,
+        opts: &ExecOptions,
+    ) -> Result<BoxedPty> {
         trace!("try to exec command");
-        let pty = openpty_local(win_size, command)?;
+        let pty = openpty_local(win_size, command, opts)?;
         Ok(pty)
     }
     async fn open(&self, path: &str, flags: OpenFlags, attr: FileAttributes) -> Result<BoxedFile> {
@@ -183,7 +262,11 @@ impl UserImpl for This {
         let file = loop {
             match open_options.open(&path2).await {
                 Ok(file) => break Ok(file),
-                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                //NOTE:only a new file gets its parents, a missing one is reported as is
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::NotFound
+                        && flags.contains(OpenFlags::CREATE) =>
+                {
                     let parent = path2.parent().unwrap();
                     debug!("try to create dir {}", parent.display());
                     tokio::fs::create_dir_all(parent).await?;
@@ -196,6 +279,29 @@ impl UserImpl for This {
     }
 }
 
+#[cfg(not(windows))]
+async fn kill_tree(child: &mut tokio::process::Child) -> std::io::Result<()> {
+    use rustix::process::{Pid, Signal, kill_process_group};
+    if let Some(pid) = child.id().and_then(|id| Pid::from_raw(id as i32)) {
+        kill_process_group(pid, Signal::KILL)?;
+    }
+    child.kill().await
+}
+
+#[cfg(windows)]
+async fn kill_tree(child: &mut tokio::process::Child) -> std::io::Result<()> {
+    if let Some(pid) = child.id() {
+        //NOTE:best effort, the direct child is killed below anyway
+        let _ = tokio::process::Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+    }
+    child.kill().await
+}
+
 #[cfg(not(windows))]
 pub fn exit_status2exit_code(es: std::process::ExitStatus) -> i32 {
     use std::os::unix::process::ExitStatusExt;
@@ -207,3 +313,14 @@ pub fn exit_status2exit_code(es: std::process::ExitStatus) -> i32 {
 pub fn exit_status2exit_code(es: std::process::ExitStatus) -> i32 {
     es.code().unwrap_or(1)
 }
+
+#[cfg(not(windows))]
+pub fn exit_status2signal(es: std::process::ExitStatus) -> Option<String> {
+    use std::os::unix::process::ExitStatusExt;
+    es.signal().map(Output::signal_name)
+}
+
+#[cfg(windows)]
+pub fn exit_status2signal(_: std::process::ExitStatus) -> Option<String> {
+    None
+}
@@ -1,39 +1,126 @@
-use std::{borrow::Cow, collections::HashMap};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+};
 
-use crate::whatever;
+use crate::{
+    Error,
+    process::{DynInteractor, OutputSink},
+    whatever,
+};
 
 use super::dev::{self, *};
 use resplus::attach;
-use russh::client;
+use russh::{
+    ChannelMsg, Sig, client,
+    keys::{self, ssh_key::PublicKey},
+};
 use russh_sftp::{client::SftpSession, protocol::StatusCode};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tracing::{debug, info, warn};
 mod config;
 pub use config::create;
+mod auth;
 mod file;
+mod host_key;
+mod options;
+mod proxy;
+mod pty;
+mod shell;
+mod target;
+mod tmp;
 
-struct Client {}
+struct Client {
+    host: String,
+    port: u16,
+    known_hosts: PathBuf,
+    /// the server key, if it is not recorded in known_hosts yet
+    unknown: Arc<Mutex<Option<PublicKey>>>,
+}
 
 impl client::Handler for Client {
-    type Error = russh::Error;
+    type Error = Error;
 
     async fn check_server_key(
         &mut self,
-        _: &russh::keys::ssh_key::PublicKey,
+        server_public_key: &PublicKey,
     ) -> std::result::Result<bool, Self::Error> {
-        Ok(true)
+        match keys::check_known_hosts_path(
+            &self.host,
+            self.port,
+            server_public_key,
+            &self.known_hosts,
+        ) {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                //NOTE:the decision is made after the handshake, before any credential is sent
+                *self.unknown.lock().unwrap() = Some(server_public_key.clone());
+                Ok(true)
+            }
+            Err(keys::Error::KeyChanged { line }) => Err(Error::HostKeyChanged {
+                host: self.host.clone(),
+                line,
+            }),
+            Err(e) => Err(e.into()),
+        }
     }
 }
 
-pub(crate) struct SSHSession {
+/// Whether opening failed for a missing parent which should be created, a missing file
+/// is reported as is unless it is to be created.
+fn missing_parent(flags: OpenFlags, e: &russh_sftp::client::error::Error) -> bool {
+    flags.contains(OpenFlags::CREATE)
+        && matches!(e, russh_sftp::client::error::Error::Status(s) if s.status_code == StatusCode::NoSuchFile)
+}
+
+/// The name of a signal without `SIG`, e.g. `KILL`.
+fn signal_name(sig: Sig) -> String {
+    match sig {
+        Sig::Custom(name) => name,
+        sig => format!("{:?}", sig),
+    }
+}
+
+/// A live session with its sftp channel.
+struct Conn {
     session: client::Handle<Client>,
-    sftp: SftpSession,
+    /// sessions of the jump hosts, which must live as long as `session`
+    _jumps: Vec<client::Handle<Client>>,
+    /// `None` if the server has no sftp subsystem, file operations then fall back to the shell
+    sftp: Option<SftpSession>,
+}
+
+pub(crate) struct SSHSession {
+    conn: RwLock<Arc<Conn>>,
+    reconnect: tokio::sync::Mutex<config::Reconnect>,
     env: HashMap<String, String>,
     home: Option<String>,
+    /// where scripts are uploaded, the home directory if `None`
+    tmpdir: Option<String>,
+    /// the shell which runs commands on the server, for quoting
+    shell: ScriptExecutor,
     command_util: BoxedCommandUtil<Self>,
 }
 
 impl SSHSession {
+    /// The live connection, established again if the previous one is broken.
+    async fn conn(&self) -> Result<Arc<Conn>> {
+        let conn = self.conn.read().unwrap().clone();
+        if !conn.session.is_closed() {
+            return Ok(conn);
+        }
+        let reconnect = self.reconnect.lock().await;
+        let current = self.conn.read().unwrap().clone();
+        if !Arc::ptr_eq(&conn, &current) {
+            //NOTE:reconnected by another operation while waiting
+            return Ok(current);
+        }
+        let conn = Arc::new(reconnect.establish().await?);
+        *self.conn.write().unwrap() = conn.clone();
+        Ok(conn)
+    }
     fn canonicalize<'a, 'b: 'a>(&'b self, path: &'a str) -> Result<std::borrow::Cow<'a, str>> {
         let path: Cow<str> = if let Some(path) = path.strip_prefix("~") {
             let Some(home) = self.home.as_deref() else {
@@ -66,65 +153,128 @@ impl SSHSession {
         new.push_str(&path[last_match..]);
         Ok(new.into())
     }
-    async fn prepare_command(&self, command: Script<'_, '_>) -> Result<String> {
-        let cmd = match command {
-            Script::Whole(cmd) => cmd.to_string(),
-            Script::Split { program, args } => {
-                let mut cmd = program.to_string();
-                for arg in args {
-                    cmd.push(' ');
-                    cmd.push_str(arg);
-                }
-                cmd
-            }
+    /// The command line to run, and the uploaded script if any, which must be kept
+    /// until the command is done.
+    async fn prepare_command(
+        &self,
+        conn: &Arc<Conn>,
+        command: Script<'_, '_>,
+        opts: &ExecOptions,
+    ) -> Result<(String, Option<tmp::ScriptFile>)> {
+        let (cmd, script) = match command {
+            Script::Whole(cmd) => (cmd.to_string(), None),
+            Script::Split { program, args } => (self.shell.join(program, args), None),
             Script::Script { executor, input } => {
                 let mut retry = 5;
-                let mut name = String::with_capacity(4 + 6);
-                loop {
-                    //TODO:extract to a function?
-                    name.push_str(".tmp");
-                    for c in std::iter::repeat_with(fastrand::alphanumeric).take(6) {
-                        name.push(c);
-                    }
-                    use russh_sftp::protocol::OpenFlags;
-                    let res = self
-                        .sftp
-                        .open_with_flags(
-                            &name,
-                            OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::EXCLUDE,
-                        )
-                        .await;
+                let name = loop {
+                    let name = tmp::script_name(self.tmpdir.as_deref(), executor.suffix());
+                    let flags = OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::EXCLUDE;
+                    let attr = FileAttributes {
+                        permissions: Some(0o600),
+                        ..Default::default()
+                    };
+                    let res = match &conn.sftp {
+                        Some(sftp) => sftp
+                            .open_with_flags_and_attributes(&name, flags.into(), attr)
+                            .await
+                            .map(|file| Box::new(file) as BoxedFile)
+                            .map_err(|e| e.into()),
+                        None => shell::open(&conn.session, &name, flags, attr).await,
+                    };
                     if let Ok(mut file) = res {
                         file.write_all(&executor.prepare_clean()).await?;
                         for blk in input {
                             file.write_all(blk.as_bytes()).await?;
                         }
-                        break;
+                        file.shutdown().await?;
+                        break name;
                     } else if retry == 0 {
                         res?;
                     }
                     retry -= 1;
-                    name.clear();
+                };
+                //NOTE:uploaded relative to home without a tmpdir, but the command may run elsewhere
+                let path = match (&self.tmpdir, &self.home) {
+                    (None, Some(home)) => format!("{}/{}", home, name),
+                    _ => name.clone(),
+                };
+                let cmd = self.shell.join(executor.as_ref(), [path.as_str()]);
+                (cmd, Some(tmp::ScriptFile::new(conn.clone(), name)))
+            }
+        };
+        Ok((opts.wrap(&self.shell, cmd)?, script))
+    }
+    async fn run(
+        &self,
+        command: Script<'_, '_>,
+        opts: &ExecOptions,
+        interactor: Option<&DynInteractor>,
+    ) -> Result<Output> {
+        let conn = self.conn().await?;
+        let mut channel = conn.session.channel_open_session().await?;
+        let (cmd, _script) = self.prepare_command(&conn, command, opts).await?;
+        info!("exec {}", cmd);
+        channel.exec(true, cmd).await?;
+        let (mut stdout, mut stderr) = (OutputSink::new(interactor), OutputSink::new(interactor));
+        let (mut code, mut signal) = (None, None);
+        let collect = async {
+            while let Some(msg) = channel.wait().await {
+                match msg {
+                    ChannelMsg::Data { data } => stdout.push(&data).await,
+                    ChannelMsg::ExtendedData { data, ext: 1 } => stderr.push(&data).await,
+                    ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status as i32),
+                    ChannelMsg::ExitSignal {
+                        signal_name: sig,
+                        error_message,
+                        ..
+                    } => {
+                        let name = signal_name(sig);
+                        debug!("killed by {} {}", name, error_message);
+                        signal = Some(name);
+                    }
+                    _ => {}
                 }
-                let cmd = format!("{} {}", executor, name);
-                cmd
             }
         };
-        Ok(cmd)
+        match opts.timeout {
+            Some(timeout) => {
+                if tokio::time::timeout(timeout, collect).await.is_err() {
+                    //NOTE:servers may ignore the signal, closing the channel hangs up the process
+                    if let Err(e) = channel.signal(Sig::KILL).await {
+                        debug!("signal failed: {}", e);
+                    }
+                    channel.close().await?;
+                    whatever!("timed out after {:?}", timeout)
+                }
+            }
+            None => collect.await,
+        }
+        debug!("exec done");
+        let code = match (code, &signal) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => Output::signal_code(signal),
+            (None, None) => whatever!("exec exited without status"),
+        };
+        Ok(Output {
+            code,
+            signal,
+            stdout: stdout.finish().await,
+            stderr: stderr.finish().await,
+        })
     }
-    async fn create_parent(&self, path: &str) -> Result<()> {
+    async fn create_parent(&self, sftp: &SftpSession, path: &str) -> Result<()> {
         let Some((parent, _)) = path.rsplit_once("/") else {
             whatever!("invalid path {}", path)
         };
         debug!("try create dir {}", parent);
-        match self.sftp.create_dir(parent).await {
+        match sftp.create_dir(parent).await {
             Ok(_) => Ok(()),
             Err(russh_sftp::client::error::Error::Status(s))
                 if s.status_code == StatusCode::NoSuchFile
                     || s.status_code == StatusCode::Failure//NOTE:why failure?
             =>
             {
-                Box::pin(self.create_parent(parent)).await
+                Box::pin(self.create_parent(sftp, parent)).await
             }
             Err(e) => Err(e)?,
         }
@@ -139,20 +289,34 @@ impl UserImpl for SSHSession {
             return (path.into(), Err(path2.unwrap_err()));
         }
         let path = path2.unwrap();
-        (
-            path.to_string().into(),
-            self.sftp.metadata(path).await.map_err(|e| e.into()),
-        )
+        let conn = match self.conn().await {
+            Ok(conn) => conn,
+            Err(e) => return (path.to_string().into(), Err(e)),
+        };
+        let attr = match &conn.sftp {
+            Some(sftp) => sftp.metadata(path.as_ref()).await.map_err(|e| e.into()),
+            None => shell::stat(&conn.session, &path).await,
+        };
+        (path.to_string().into(), attr)
     }
-    async fn glob_file_meta(&self, path: &XPath) -> crate::Result<Vec<Metadata>> {
-        let metadata = self.sftp.metadata(path.to_string()).await?;
+    async fn glob_file_meta(&self, path: &XPath, ignore: &Ignore) -> crate::Result<Vec<Metadata>> {
+        let conn = self.conn().await?;
+        let Some(sftp) = &conn.sftp else {
+            return shell::walk(&conn.session, path.as_str(), ignore).await;
+        };
+        let metadata = sftp.metadata(path.to_string()).await?;
         if metadata.is_dir() {
             let mut stack = vec![path.to_string()];
             let prefix = format!("{path}/");
             let mut infos = Vec::new();
             while let Some(path) = stack.pop() {
-                for entry in self.sftp.read_dir(&path).await? {
+                for entry in sftp.read_dir(&path).await? {
                     let sub_path = format!("{}/{}", path, entry.file_name());
+                    let rel_path = sub_path.strip_prefix(&prefix).unwrap();
+                    if ignore.is_ignored(rel_path, entry.file_type().is_dir()) {
+                        debug!("ignore {}", sub_path);
+                        continue;
+                    }
                     if entry.file_type().is_dir() {
                         stack.push(sub_path);
                         continue;
@@ -162,7 +326,7 @@ impl UserImpl for SSHSession {
                         continue;
                     }
                     infos.push(Metadata {
-                        path: sub_path.strip_prefix(&prefix).unwrap().to_string().into(),
+                        path: rel_path.to_string().into(),
                         attr: entry.metadata(),
                     });
                 }
@@ -182,6 +346,16 @@ impl UserImpl for SSHSession {
         }
         Ok(())
     }
+    async fn remove(&self, path: &str) -> Result<()> {
+        let path2 = self.canonicalize(path)?;
+        let path = path2.as_ref();
+        let conn = self.conn().await?;
+        match &conn.sftp {
+            Some(sftp) => sftp.remove_file(path).await?,
+            None => shell::remove(&conn.session, path).await?,
+        }
+        Ok(())
+    }
     async fn auto(&self, name: &str, action: &str, _: Option<&str>) -> crate::Result<()> {
         //TODO:`destroy` action
         let ec = match action {
@@ -195,24 +369,25 @@ impl UserImpl for SSHSession {
         }
         Ok(())
     }
-    async fn exec(&self, command: Script<'_, '_>) -> Result<Output> {
-        let channel = self.session.channel_open_session().await?;
-        let cmd = self.prepare_command(command).await?;
-        info!("exec {}", cmd);
-        channel.exec(true, cmd).await?;
-        let mut pty = channel.into_pty();
-        let mut stdout = Vec::new();
-        pty.reader.read_to_end(&mut stdout).await?;
-        let code = pty.ctl.wait().await?;
-        debug!("exec done");
-        Ok(Output {
-            code,
-            stdout,
-            stderr: Vec::new(),
-        })
+    async fn exec(&self, command: Script<'_, '_>, opts: &ExecOptions) -> Result<Output> {
+        self.run(command, opts, None).await
     }
-    async fn pty(&self, command: Script<'_, '_>, win_size: WindowSize) -> Result<BoxedPty> {
-        let channel = self.session.channel_open_session().await?;
+    async fn exec_stream(
+        &self,
+        command: Script<'_, '_>,
+        opts: &ExecOptions,
+        interactor: &DynInteractor,
+    ) -> Result<Output> {
+        self.run(command, opts, Some(interactor)).await
+    }
+    async fn pty(
+        &self,
+        command: Script<'_, '_>,
+        win_size: WindowSize,
+        opts: &ExecOptions,
+    ) -> Result<BoxedPty> {
+        let conn = self.conn().await?;
+        let channel = conn.session.channel_open_session().await?;
         channel
             .request_pty(
                 true,
@@ -224,27 +399,32 @@ impl UserImpl for SSHSession {
                 &[],
             )
             .await?;
-        // info!("exec {}", command);
-        let cmd = self.prepare_command(command).await?;
+        let (cmd, script) = self.prepare_command(&conn, command, opts).await?;
+        info!("pty {}", cmd);
         channel.exec(true, cmd).await?;
-        Ok(channel.into_pty())
+        let pty = pty::open(channel).with_timeout(opts.timeout);
+        Ok(match script {
+            Some(script) => pty.with_guard(script),
+            None => pty,
+        })
     }
     async fn open(&self, path: &str, flags: OpenFlags, attr: FileAttributes) -> Result<BoxedFile> {
         let path2 = self.canonicalize(path)?;
         let path = path2.as_ref();
 
         let open_flags = flags.into();
+        let conn = self.conn().await?;
+        let Some(sftp) = &conn.sftp else {
+            return shell::open(&conn.session, path, flags, attr).await;
+        };
         let file = loop {
-            match self
-                .sftp
+            match sftp
                 .open_with_flags_and_attributes(path, open_flags, attr.clone())
                 .await
             {
                 Ok(file) => break Ok(file),
-                Err(russh_sftp::client::error::Error::Status(s))
-                    if s.status_code == StatusCode::NoSuchFile =>
-                {
-                    attach!(self.create_parent(path), ..).await?;
+                Err(e) if missing_parent(flags, &e) => {
+                    attach!(self.create_parent(sftp, path), ..).await?;
                 }
                 Err(e) => break Err(e),
             }
@@ -252,3 +432,30 @@ impl UserImpl for SSHSession {
         Ok(Box::new(file))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use russh_sftp::{client::error::Error as SftpError, protocol::Status};
+
+    use super::*;
+
+    fn no_such_file() -> SftpError {
+        SftpError::Status(Status {
+            id: 0,
+            status_code: StatusCode::NoSuchFile,
+            error_message: "no such file".to_string(),
+            language_tag: "en-US".to_string(),
+        })
+    }
+
+    #[test]
+    fn missing_file_is_not_found() {
+        assert!(!missing_parent(OpenFlags::READ, &no_such_file()));
+        assert!(missing_parent(
+            OpenFlags::WRITE | OpenFlags::CREATE,
+            &no_such_file()
+        ));
+        let res: crate::Result<()> = Err(no_such_file()).map_err(Into::into);
+        assert!(res.unwrap_err().is_not_found());
+    }
+}
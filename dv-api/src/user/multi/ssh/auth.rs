@@ -0,0 +1,505 @@
+use std::path::Path;
+
+use resplus::flog;
+use russh::{
+    MethodKind, MethodSet,
+    client::{AuthResult, Handle, KeyboardInteractiveAuthResponse},
+    keys::{
+        self,
+        ssh_key::{HashAlg, PublicKey},
+    },
+};
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+use crate::{Error, process::DynInteractor, user::Keyring};
+
+use super::{Client, dev::*};
+
+/// Drives the authentication of one session, remembering the methods the server still accepts.
+pub struct Auth<'a> {
+    session: &'a mut Handle<Client>,
+    user: &'a str,
+    interactor: &'a DynInteractor,
+    keyring: &'a Keyring,
+    remaining: MethodSet,
+    /// the server refused the saved password, so it is not sent again
+    saved_rejected: bool,
+}
+
+impl<'a> Auth<'a> {
+    /// Try `none` first, returns `None` if the server already accepts the user.
+    pub async fn start(
+        session: &'a mut Handle<Client>,
+        user: &'a str,
+        interactor: &'a DynInteractor,
+        keyring: &'a Keyring,
+    ) -> Result<Option<Self>> {
+        let res = flog!(session.authenticate_none(user)).await?;
+        let AuthResult::Failure {
+            remaining_methods, ..
+        } = res
+        else {
+            return Ok(None);
+        };
+        debug!("authenticate_none failed");
+        Ok(Some(Self {
+            session,
+            user,
+            interactor,
+            keyring,
+            remaining: remaining_methods,
+            saved_rejected: false,
+        }))
+    }
+    fn offers(&self, kind: MethodKind) -> bool {
+        self.remaining.contains(&kind)
+    }
+    fn check(&mut self, res: AuthResult) -> bool {
+        match res {
+            AuthResult::Success => true,
+            AuthResult::Failure {
+                remaining_methods, ..
+            } => {
+                self.remaining = remaining_methods;
+                false
+            }
+        }
+    }
+    async fn rsa_hash(&self, key: &PublicKey) -> Result<Option<HashAlg>> {
+        if !key.algorithm().is_rsa() {
+            return Ok(None);
+        }
+        Ok(self.session.best_supported_rsa_hash().await?.flatten())
+    }
+
+    /// Try the identities held by the ssh-agent behind `SSH_AUTH_SOCK`,
+    /// restricted to `only` if given.
+    #[cfg(unix)]
+    pub async fn agent(&mut self, only: Option<&[PublicKey]>) -> Result<bool> {
+        if !self.offers(MethodKind::PublicKey) {
+            return Ok(false);
+        }
+        let Some(sock) = std::env::var_os("SSH_AUTH_SOCK") else {
+            debug!("ssh-agent unavailable: SSH_AUTH_SOCK is not set");
+            return Ok(false);
+        };
+        let Some((mut agent, identities)) = agent_identities(Path::new(&sock)).await else {
+            return Ok(false);
+        };
+        for key in identities {
+            if !self.offers(MethodKind::PublicKey) {
+                break;
+            }
+            if only.is_some_and(|only| !only.iter().any(|k| k.key_data() == key.key_data())) {
+                debug!("skip agent identity {}", key.comment());
+                continue;
+            }
+            let hash_alg = self.rsa_hash(&key).await?;
+            let comment = key.comment().to_string();
+            let res = self
+                .session
+                .authenticate_publickey_with(self.user, key, hash_alg, &mut agent)
+                .await
+                .map_err(Error::unknown)?;
+            if self.check(res) {
+                info!("authenticated with agent identity {}", comment);
+                return Ok(true);
+            }
+            debug!("agent identity {} rejected", comment);
+        }
+        Ok(false)
+    }
+    #[cfg(not(unix))]
+    pub async fn agent(&mut self, _: Option<&[PublicKey]>) -> Result<bool> {
+        debug!("ssh-agent is not supported on this platform");
+        Ok(false)
+    }
+
+    /// Try a private key file, `passphrase` is asked through the interactor if the key is encrypted
+    /// and none is given.
+    pub async fn key(&mut self, path: &Path, passphrase: Option<&str>) -> Result<bool> {
+        if !self.offers(MethodKind::PublicKey) {
+            return Ok(false);
+        }
+        if !path.exists() {
+            debug!("identity {} not found", path.display());
+            return Ok(false);
+        }
+        let kp = match self
+            .keyring
+            .load_key(path, passphrase, self.interactor)
+            .await
+        {
+            Ok(kp) => kp,
+            Err(e) => {
+                warn!("load identity {} failed: {}", path.display(), e);
+                return Ok(false);
+            }
+        };
+        let hash_alg = self.rsa_hash(kp.public_key()).await?;
+        let private_key = keys::PrivateKeyWithHashAlg::new(kp, hash_alg);
+        let res = flog!(
+            self.session.authenticate_publickey(self.user, private_key),
+            0
+        )
+        .await?;
+        if self.check(res) {
+            return Ok(true);
+        }
+        warn!("authenticate_publickey with {} failed", path.display());
+        Ok(false)
+    }
+
+    fn login(&self, host: &str, port: u16) -> String {
+        format!("{}@{}:{}", self.user, host, port)
+    }
+
+    /// The password accepted before, unless the server refused it in this session.
+    async fn saved(&self, login: &str) -> Option<Zeroizing<String>> {
+        match self.saved_rejected {
+            true => None,
+            false => self.keyring.password(login).await,
+        }
+    }
+
+    /// Try the password accepted before, which is all a reconnect can use without asking.
+    pub async fn saved_password(&mut self, host: &str, port: u16) -> Result<bool> {
+        if !self.offers(MethodKind::Password) {
+            return Ok(false);
+        }
+        let Some(passwd) = self.saved(&self.login(host, port)).await else {
+            return Ok(false);
+        };
+        let accepted = self.password(&passwd).await?;
+        self.saved_rejected = !accepted;
+        Ok(accepted)
+    }
+
+    /// Ask for the password through the interactor, the accepted one is kept in the keyring.
+    pub async fn ask_password(&mut self, host: &str, port: u16) -> Result<bool> {
+        if !self.offers(MethodKind::Password) {
+            return Ok(false);
+        }
+        let (interactor, keyring) = (self.interactor, self.keyring);
+        let login = self.login(host, port);
+        let prompt = format!("{}@{}'s password:", self.user, host);
+        retry_password(interactor, keyring, login, prompt, async |passwd| {
+            Ok(match self.password(passwd).await? {
+                true => Some(true),
+                false => self.offers(MethodKind::Password).then_some(false),
+            })
+        })
+        .await
+    }
+
+    /// Answer the challenges of `keyboard-interactive` through the interactor, e.g. for 2FA or PAM.
+    /// A lone secret prompt is taken as the password, so it is answered from the keyring first,
+    /// unless `saved_password` was refused, and the accepted answer is kept there.
+    pub async fn keyboard_interactive(&mut self, host: &str, port: u16) -> Result<bool> {
+        let login = self.login(host, port);
+        if let Some(saved) = self.saved(&login).await
+            && self.challenge(&login, Some(saved.as_str())).await?
+        {
+            return Ok(true);
+        }
+        self.challenge(&login, None).await
+    }
+
+    async fn challenge(&mut self, login: &str, saved: Option<&str>) -> Result<bool> {
+        if !self.offers(MethodKind::KeyboardInteractive) {
+            return Ok(false);
+        }
+        //NOTE:only a single round with a single secret may be remembered as the password
+        let mut secret = None;
+        let mut rounds = 0;
+        let mut res = flog!(
+            self.session
+                .authenticate_keyboard_interactive_start(self.user, None),
+            0
+        )
+        .await?;
+        loop {
+            match res {
+                KeyboardInteractiveAuthResponse::Success => {
+                    if saved.is_none()
+                        && rounds == 1
+                        && let Some(secret) = secret
+                    {
+                        self.keyring
+                            .remember_password(login.to_string(), secret)
+                            .await;
+                    }
+                    return Ok(true);
+                }
+                KeyboardInteractiveAuthResponse::Failure {
+                    remaining_methods, ..
+                } => {
+                    self.remaining = remaining_methods;
+                    warn!("authenticate_keyboard_interactive failed");
+                    return Ok(false);
+                }
+                KeyboardInteractiveAuthResponse::InfoRequest {
+                    name,
+                    instructions,
+                    prompts,
+                } => {
+                    if saved.is_none() {
+                        for hint in [name, instructions] {
+                            if !hint.is_empty() {
+                                self.interactor.log(hint).await;
+                            }
+                        }
+                    }
+                    let prompts: Vec<_> = prompts.into_iter().map(|p| (p.prompt, p.echo)).collect();
+                    let Some(responses) = answer(self.interactor, &prompts, saved).await else {
+                        return Ok(false);
+                    };
+                    //NOTE:some PAM stacks send an empty round after the password
+                    if !prompts.is_empty() {
+                        rounds += 1;
+                        secret = lone_secret(&prompts, &responses);
+                    }
+                    res = flog!(
+                        self.session
+                            .authenticate_keyboard_interactive_respond(responses),
+                        0
+                    )
+                    .await?;
+                }
+            }
+        }
+    }
+
+    pub async fn password(&mut self, passwd: &str) -> Result<bool> {
+        if !self.offers(MethodKind::Password) {
+            return Ok(false);
+        }
+        let res = flog!(self.session.authenticate_password(self.user, passwd), 0).await?;
+        if self.check(res) {
+            return Ok(true);
+        }
+        warn!("authenticate_password failed");
+        Ok(false)
+    }
+}
+
+/// Ask for a password up to 3 times until `attempt` accepts it, and keep the accepted one in
+/// the keyring under `login`. `attempt` gives `None` once the server stops offering passwords.
+async fn retry_password(
+    interactor: &DynInteractor,
+    keyring: &Keyring,
+    login: String,
+    prompt: String,
+    mut attempt: impl AsyncFnMut(&str) -> Result<Option<bool>>,
+) -> Result<bool> {
+    for _ in 0..3 {
+        let passwd = Zeroizing::new(interactor.prompt(prompt.clone(), false).await?);
+        let res = attempt(passwd.as_str()).await?;
+        if res == Some(true) {
+            keyring.remember_password(login, passwd).await;
+            return Ok(true);
+        }
+        interactor
+            .log("Permission denied, please try again.".to_string())
+            .await;
+        if res.is_none() {
+            break;
+        }
+    }
+    Ok(false)
+}
+
+/// The answer of a round with a single hidden prompt, which is taken as the password.
+fn lone_secret(prompts: &[(String, bool)], responses: &[String]) -> Option<Zeroizing<String>> {
+    match (responses, prompts) {
+        ([response], [(_, false)]) => Some(Zeroizing::new(response.clone())),
+        _ => None,
+    }
+}
+
+/// The responses to one round of `keyboard-interactive` prompts, given as text and echo.
+/// `saved` answers a lone secret prompt, otherwise the interactor is asked, and `None` means
+/// the prompts can not be answered, e.g. nobody may be asked while reconnecting.
+async fn answer(
+    interactor: &DynInteractor,
+    prompts: &[(String, bool)],
+    saved: Option<&str>,
+) -> Option<Vec<String>> {
+    if let Some(saved) = saved {
+        return match prompts {
+            [(_, false)] => Some(vec![saved.to_string()]),
+            _ => None,
+        };
+    }
+    let mut responses = Vec::with_capacity(prompts.len());
+    for (prompt, echo) in prompts {
+        match interactor.prompt(prompt.clone(), *echo).await {
+            Ok(response) => responses.push(response),
+            Err(e) => {
+                debug!("give up keyboard-interactive: {}", e);
+                return None;
+            }
+        }
+    }
+    Some(responses)
+}
+
+/// Connect to the ssh-agent at `sock` and list its identities, a broken agent is only logged
+/// so that the other methods are still tried.
+#[cfg(unix)]
+async fn agent_identities(
+    sock: &Path,
+) -> Option<(
+    keys::agent::client::AgentClient<tokio::net::UnixStream>,
+    Vec<PublicKey>,
+)> {
+    use keys::agent::client::AgentClient;
+    let mut agent = match AgentClient::connect_uds(sock).await {
+        Ok(agent) => agent,
+        Err(e) => {
+            debug!("ssh-agent unavailable: {}", e);
+            return None;
+        }
+    };
+    match agent.request_identities().await {
+        Ok(identities) => {
+            debug!("ssh-agent offers {} identities", identities.len());
+            Some((agent, identities))
+        }
+        Err(e) => {
+            warn!("list ssh-agent identities failed: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{super::config::Unattended, *};
+    use crate::process::tests::Scripted;
+
+    fn prompts(prompts: &[(&str, bool)]) -> Vec<(String, bool)> {
+        prompts.iter().map(|(p, e)| (p.to_string(), *e)).collect()
+    }
+
+    #[tokio::test]
+    async fn answer_prompts() {
+        let int = Scripted::new(&["alice", "123456"]);
+        let responses = answer(&int, &prompts(&[("User:", true), ("Code:", false)]), None).await;
+        assert_eq!(responses.unwrap(), ["alice", "123456"]);
+        assert_eq!(
+            *int.prompts.lock().unwrap(),
+            prompts(&[("User:", true), ("Code:", false)])
+        );
+    }
+
+    #[tokio::test]
+    async fn answer_saved_without_asking() {
+        let int = Scripted::new(&[]);
+        let responses = answer(&int, &prompts(&[("Password:", false)]), Some("secret")).await;
+        assert_eq!(responses.unwrap(), ["secret"]);
+        assert!(int.prompts.lock().unwrap().is_empty());
+        let responses = answer(&int, &prompts(&[("Code:", true)]), Some("secret")).await;
+        assert!(responses.is_none(), "saved password is only for a secret");
+    }
+
+    #[tokio::test]
+    async fn unattended_gives_up() {
+        let responses = answer(&Unattended, &prompts(&[("Password:", false)]), None).await;
+        assert!(responses.is_none());
+        let responses = answer(
+            &Unattended,
+            &prompts(&[("Password:", false)]),
+            Some("secret"),
+        )
+        .await;
+        assert_eq!(responses.unwrap(), ["secret"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn broken_agent_falls_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let sock = dir.path().join("agent.sock");
+        assert!(agent_identities(&sock).await.is_none());
+        let listener = tokio::net::UnixListener::bind(&sock).unwrap();
+        //NOTE:hang up on the first request like a crashed agent
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                drop(stream);
+            }
+        });
+        assert!(agent_identities(&sock).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn retry_then_remember() {
+        let int = Scripted::new(&["wrong", "right"]);
+        let keyring = Keyring::default();
+        let accepted = retry_password(
+            &int,
+            &keyring,
+            "me@host:22".to_string(),
+            "me@host's password:".to_string(),
+            async |passwd| Ok(Some(passwd == "right")),
+        )
+        .await
+        .unwrap();
+        assert!(accepted);
+        assert_eq!(
+            *int.prompts.lock().unwrap(),
+            prompts(&[
+                ("me@host's password:", false),
+                ("me@host's password:", false)
+            ])
+        );
+        assert_eq!(
+            *int.logs.lock().unwrap(),
+            ["Permission denied, please try again."]
+        );
+        let saved = keyring.password("me@host:22").await.unwrap();
+        assert_eq!(saved.as_str(), "right");
+        //NOTE:a reconnect answers the password prompt from the keyring
+        let int = Scripted::new(&[]);
+        let responses = answer(
+            &int,
+            &prompts(&[("Password:", false)]),
+            Some(saved.as_str()),
+        )
+        .await;
+        assert_eq!(responses.unwrap(), ["right"]);
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up() {
+        let int = Scripted::new(&["a", "b", "c", "d"]);
+        let keyring = Keyring::default();
+        let login = "me@host:22".to_string();
+        let res = retry_password(&int, &keyring, login.clone(), String::new(), async |_| {
+            Ok(Some(false))
+        })
+        .await;
+        assert!(!res.unwrap());
+        assert_eq!(int.prompts.lock().unwrap().len(), 3);
+        assert!(keyring.password(&login).await.is_none());
+        let int = Scripted::new(&["a", "b"]);
+        let res = retry_password(&int, &keyring, login, String::new(), async |_| Ok(None)).await;
+        assert!(!res.unwrap());
+        assert_eq!(int.prompts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn remember_lone_secret() {
+        let responses = ["secret".to_string()];
+        assert_eq!(
+            lone_secret(&prompts(&[("Password:", false)]), &responses)
+                .as_deref()
+                .map(String::as_str),
+            Some("secret")
+        );
+        assert!(lone_secret(&prompts(&[("User:", true)]), &responses).is_none());
+        let responses = ["me".to_string(), "123456".to_string()];
+        assert!(lone_secret(&prompts(&[("User:", true), ("Code:", false)]), &responses).is_none());
+    }
+}
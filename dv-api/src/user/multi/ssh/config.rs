@@ -1,36 +1,74 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
 
+use async_trait::async_trait;
 use resplus::{attach, flog};
-use russh::client::{self, AuthResult, Handle};
-use tokio::io::AsyncReadExt;
-use tracing::{info, warn};
+use russh::{
+    ChannelStream,
+    client::{self, Handle},
+    keys,
+};
+use russh_sftp::client::SftpSession;
+use tokio::{io::AsyncReadExt, net::TcpStream};
+use tracing::{debug, info, warn};
 
-use crate::whatever;
+use crate::{
+    process::{DynInteractor, Interactor},
+    user::Keyring,
+    whatever,
+};
 
-use super::{Client, SSHSession, dev::*};
+use super::{
+    Client, Conn, SSHSession,
+    auth::Auth,
+    dev::*,
+    host_key::{self, HostKeyPolicy},
+    options::HostOptions,
+    proxy::ProxyStream,
+    target::Target,
+    tmp,
+};
 
-pub async fn create(host: String, mut cfg: Config, dev: Option<Arc<Dev>>) -> Result<User> {
-    let (h, user) = attach!(connect(&host, cfg.get("passwd").cloned()), 0).await?;
+pub async fn create(
+    host: String,
+    mut cfg: Config,
+    dev: Option<Arc<Dev>>,
+    interactor: &DynInteractor,
+    keyring: &Arc<Keyring>,
+) -> Result<User> {
+    let (conn, user) = attach!(establish(&host, &cfg, interactor, keyring), 0).await?;
+    let reconnect = Reconnect {
+        host,
+        cfg: cfg.clone(),
+        keyring: keyring.clone(),
+    };
     cfg.entry("USER".into()).or_insert(user.clone());
     let os = cfg.get("OS").map(|s| s.as_str()).unwrap_or("");
     let mut os = os.into();
-    let env = detect2(&h, &mut os).await?;
+    let env = detect2(&conn.session, &mut os).await?;
     let command_util = (&os).into();
-    let channel = flog!(h.channel_open_session()).await?;
-    flog!(channel.request_subsystem(true, "sftp")).await?;
-    let sftp = russh_sftp::client::SftpSession::new(channel.into_stream()).await?;
     let home = match os {
         Os::Linux(_) | Os::Mac | Os::Unix => env.get("HOME").cloned(),
         Os::Windows => env.get("HOMEPATH").cloned(),
         _ => None,
     };
-    let sys = SSHSession {
-        session: h,
-        sftp,
+    let mut sys = SSHSession {
+        conn: RwLock::new(Arc::new(conn)),
+        reconnect: tokio::sync::Mutex::new(reconnect),
         env,
         home,
+        tmpdir: None,
+        shell: if os.is_windows() {
+            ScriptExecutor::Powershell
+        } else {
+            ScriptExecutor::Sh
+        },
         command_util,
     };
+    sys.tmpdir = tmp::locate(&sys, cfg.get("TMPDIR").map(|s| s.as_str()), &user, &os).await?;
     let u: BoxedUser = sys.into();
     let dev = match dev {
         Some(dev) => dev,
@@ -43,62 +81,299 @@ pub async fn create(host: String, mut cfg: Config, dev: Option<Arc<Dev>>) -> Res
     User::new(cfg.vars, cfg.is_system.unwrap_or(false), u, dev).await
 }
 
-async fn connect(host: &str, passwd: Option<String>) -> Result<(Handle<Client>, String)> {
-    let host_cfg = flog!(russh_config::parse_home(&host), ..)?; //with host
-    let config = client::Config::default();
-    let config = Arc::new(config);
-    let sh = Client {};
+/// What is kept to establish the session again when the connection breaks.
+pub struct Reconnect {
+    host: String,
+    cfg: Config,
+    keyring: Arc<Keyring>,
+}
+
+impl Reconnect {
+    /// Nobody may be asked in the middle of a run, so only the agent and the secrets
+    /// already in the keyring can be used.
+    pub async fn establish(&self) -> Result<Conn> {
+        warn!("connection to {} lost, reconnecting", self.host);
+        let (conn, _) = establish(&self.host, &self.cfg, &Unattended, &self.keyring).await?;
+        Ok(conn)
+    }
+}
 
-    let mut session = flog!(client::connect(
-        config,
-        (host_cfg.host_name.clone(), host_cfg.port),
-        sh
+pub(super) struct Unattended;
+
+#[async_trait]
+impl Interactor for Unattended {
+    async fn window_size(&self) -> WindowSize {
+        WindowSize { rows: 24, cols: 80 }
+    }
+    async fn log(&self, msg: String) {
+        info!("{}", msg);
+    }
+    async fn ask(&self, _: BoxedPty) -> Result<i32> {
+        whatever!("cannot attach a terminal while reconnecting")
+    }
+    async fn confirm(&self, msg: String, _: &[&str]) -> Result<usize> {
+        whatever!("cannot confirm while reconnecting: {}", msg)
+    }
+    async fn prompt(&self, msg: String, _: bool) -> Result<String> {
+        whatever!("cannot ask while reconnecting: {}", msg)
+    }
+}
+
+/// Connect and open the sftp channel, returns the login user as well.
+async fn establish(
+    host: &str,
+    cfg: &Config,
+    interactor: &DynInteractor,
+    keyring: &Keyring,
+) -> Result<(Conn, String)> {
+    let Connection {
+        handle,
+        user,
+        jumps,
+    } = connect(host, cfg, interactor, keyring).await?;
+    let sftp = match open_sftp(&handle).await {
+        Ok(sftp) => Some(sftp),
+        Err(e) => {
+            warn!(
+                "sftp unavailable on {}, fall back to the shell: {}",
+                host, e
+            );
+            None
+        }
+    };
+    let conn = Conn {
+        session: handle,
+        _jumps: jumps,
+        sftp,
+    };
+    Ok((conn, user))
+}
+
+async fn open_sftp(handle: &Handle<Client>) -> Result<SftpSession> {
+    let channel = flog!(handle.channel_open_session()).await?;
+    flog!(channel.request_subsystem(true, "sftp")).await?;
+    Ok(SftpSession::new(channel.into_stream()).await?)
+}
+
+/// An authenticated session.
+pub struct Connection {
+    pub handle: Handle<Client>,
+    pub user: String,
+    /// sessions of the jump hosts, which must live as long as `handle`
+    pub jumps: Vec<Handle<Client>>,
+}
+
+async fn connect(
+    host: &str,
+    cfg: &Config,
+    interactor: &DynInteractor,
+    keyring: &Keyring,
+) -> Result<Connection> {
+    let (target, options) = Target::resolve(host)?;
+    let jump = cfg
+        .get("JUMP")
+        .map(|s| s.as_str())
+        .or_else(|| options.get("proxyjump"))
+        .filter(|jump| !jump.eq_ignore_ascii_case("none"));
+    let hop_cfg = hop_config(cfg);
+    let mut jumps: Vec<Handle<Client>> = Vec::new();
+    for hop in jump.into_iter().flat_map(|jump| jump.split(',')) {
+        let (hop, hop_options) = Target::hop(hop)?;
+        info!("jump through {}@{}:{}", hop.user, hop.host_name, hop.port);
+        let stream = transport(&hop, &hop_options, &hop_cfg, jumps.last()).await?;
+        let handle = handshake(&hop, &hop_options, &hop_cfg, stream, interactor, keyring).await?;
+        jumps.push(handle);
+    }
+    let stream = transport(&target, &options, cfg, jumps.last()).await?;
+    let handle = handshake(&target, &options, cfg, stream, interactor, keyring).await?;
+    Ok(Connection {
+        handle,
+        user: target.user,
+        jumps,
+    })
+}
+
+/// Options of the target which apply to its jump hosts as well, the rest like `passwd`
+/// belongs to the target login only and is never sent to a jump host.
+const HOP_OPTIONS: &[&str] = &[
+    "StrictHostKeyChecking",
+    "UserKnownHostsFile",
+    "ConnectTimeout",
+    "ServerAliveInterval",
+    "ServerAliveCountMax",
+    "IdentityFile",
+    "IdentitiesOnly",
+    "passphrase",
+];
+
+/// The config to connect the jump hosts of `cfg` with.
+fn hop_config(cfg: &Config) -> Config {
+    let mut hop = Config::default();
+    for &key in HOP_OPTIONS {
+        if let Some(value) = cfg.get(key) {
+            hop.insert(key.to_string(), value.clone());
+        }
+    }
+    hop
+}
+
+/// Run `fut` within `limit` if any, like `ConnectTimeout` of ssh_config.
+async fn within<T>(
+    limit: Option<Duration>,
+    target: &Target,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(limit) = limit else {
+        return fut.await;
+    };
+    match tokio::time::timeout(limit, fut).await {
+        Ok(res) => res,
+        Err(_) => whatever!("connect {} timed out after {:?}", target.alias, limit),
+    }
+}
+
+type BoxedStream = Box<dyn AsyncStream + Unpin + Send>;
+
+/// Open the byte stream to run the session over: a tunnel through the last jump host,
+/// the stdio of `ProxyCommand`, or a plain tcp connection.
+async fn transport(
+    target: &Target,
+    options: &HostOptions,
+    cfg: &Config,
+    via: Option<&Handle<Client>>,
+) -> Result<BoxedStream> {
+    if let Some(via) = via {
+        let timeout = options.seconds(cfg, "ConnectTimeout");
+        return Ok(Box::new(
+            within(timeout, target, tunnel(via, target)).await?,
+        ));
+    }
+    if let Some(command) = options
+        .lookup(cfg, "ProxyCommand")
+        .filter(|command| !command.eq_ignore_ascii_case("none"))
+    {
+        let command = target.expand(command);
+        info!("connect {} through {}", target.alias, command);
+        return Ok(Box::new(ProxyStream::spawn(&command)?));
+    }
+    let timeout = options.seconds(cfg, "ConnectTimeout");
+    Ok(Box::new(within(timeout, target, dial(target)).await?))
+}
+
+async fn dial(target: &Target) -> Result<TcpStream> {
+    let stream = flog!(TcpStream::connect((target.host_name.as_str(), target.port))).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+/// Open a `direct-tcpip` channel to `target` through an established session.
+async fn tunnel(via: &Handle<Client>, target: &Target) -> Result<ChannelStream<client::Msg>> {
+    let channel = flog!(via.channel_open_direct_tcpip(
+        target.host_name.clone(),
+        target.port as u32,
+        "127.0.0.1",
+        0
     ))
     .await?;
+    Ok(channel.into_stream())
+}
 
-    let mut res = flog!(session.authenticate_none(&host_cfg.user)).await?;
-    let AuthResult::Failure {
-        mut remaining_methods,
-        ..
-    } = res
-    else {
-        return Ok((session, host_cfg.user));
+/// Verify the server and authenticate over an established transport.
+async fn handshake(
+    target: &Target,
+    options: &HostOptions,
+    cfg: &Config,
+    stream: BoxedStream,
+    interactor: &DynInteractor,
+    keyring: &Keyring,
+) -> Result<Handle<Client>> {
+    let mut config = client::Config::default();
+    if let Some(interval) = options.seconds(cfg, "ServerAliveInterval") {
+        config.keepalive_interval = Some(interval);
+        if let Some(max) = options.lookup(cfg, "ServerAliveCountMax") {
+            match max.parse() {
+                Ok(max) => config.keepalive_max = max,
+                Err(_) => warn!("invalid ServerAliveCountMax {}", max),
+            }
+        }
+    }
+    let config = Arc::new(config);
+    let known_hosts = host_key::known_hosts_path(options, cfg);
+    let unknown = Arc::new(Mutex::new(None));
+    let sh = Client {
+        host: target.host_name.clone(),
+        port: target.port,
+        known_hosts: known_hosts.clone(),
+        unknown: unknown.clone(),
     };
-    warn!("authenticate_none failed");
-    use russh::{MethodKind, keys};
-    if let (Some(path), true) = (
-        host_cfg.identity_file,
-        remaining_methods.contains(&MethodKind::PublicKey),
-    ) {
-        let kp = keys::load_secret_key(&path, None)?;
-        let private_key = keys::PrivateKeyWithHashAlg::new(Arc::new(kp), None);
-        res = flog!(
-            session.authenticate_publickey(&host_cfg.user, private_key,),
-            0
+
+    //NOTE:like OpenSSH, the timeout covers the key exchange as well
+    let mut session = within(options.seconds(cfg, "ConnectTimeout"), target, async {
+        Ok(flog!(client::connect_stream(config, stream, sh)).await?)
+    })
+    .await?;
+
+    let key = unknown.lock().unwrap().take();
+    if let Some(key) = key {
+        host_key::trust_new(
+            HostKeyPolicy::new(options, cfg),
+            interactor,
+            &target.host_name,
+            target.port,
+            &key,
+            &known_hosts,
         )
         .await?;
-        let AuthResult::Failure {
-            remaining_methods: s,
-            ..
-        } = res
-        else {
-            return Ok((session, host_cfg.user));
-        };
-        warn!("authenticate_publickey with {} failed", path);
-        remaining_methods = s;
-    }
-    if let (Some(passwd), true) = (passwd, remaining_methods.contains(&MethodKind::Password)) {
-        res = flog!(session.authenticate_password(&host_cfg.user, passwd), 0).await?;
-        if res.success() {
-            return Ok((session, host_cfg.user));
+    }
+
+    let Some(mut auth) = Auth::start(&mut session, &target.user, interactor, keyring).await? else {
+        return Ok(session);
+    };
+    let identities = options.identity_files(cfg, target);
+    let only = options.enabled(cfg, "IdentitiesOnly").then(|| {
+        identities
+            .iter()
+            .filter_map(|path| {
+                let mut path = path.clone().into_os_string();
+                path.push(".pub");
+                keys::load_public_key(&path)
+                    .inspect_err(|e| debug!("load {:?} failed: {}", path, e))
+                    .ok()
+            })
+            .collect::<Vec<_>>()
+    });
+    if auth.agent(only.as_deref()).await? {
+        return Ok(session);
+    }
+    let passphrase = cfg.get("passphrase").map(|s| s.as_str());
+    for path in &identities {
+        if auth.key(path, passphrase).await? {
+            return Ok(session);
         }
-        warn!("authenticate_password failed");
+    }
+    if let Some(passwd) = cfg.get("passwd")
+        && auth.password(passwd).await?
+    {
+        return Ok(session);
+    }
+    //NOTE:the saved password goes first, a reconnect can not answer anything else
+    if !cfg.contains_key("passwd") && auth.saved_password(&target.host_name, target.port).await? {
+        return Ok(session);
+    }
+    if auth
+        .keyboard_interactive(&target.host_name, target.port)
+        .await?
+    {
+        return Ok(session);
+    }
+    if !cfg.contains_key("passwd") && auth.ask_password(&target.host_name, target.port).await? {
+        return Ok(session);
     }
     whatever!(
         "ssh connect {} {} {} failed",
-        host,
-        host_cfg.host_name,
-        host_cfg.user
+        target.alias,
+        target.host_name,
+        target.user
     )
 }
 
@@ -173,3 +448,30 @@ async fn detect(h: &Handle<Client>, os: &mut Os) -> Result<HashMap<String, Strin
 
     Ok(env)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hop_inherits_connection_options() {
+        let mut cfg = Config::default();
+        for (key, value) in [
+            ("StrictHostKeyChecking", "yes"),
+            ("IdentityFile", "~/.ssh/deploy"),
+            ("passphrase", "phrase"),
+            ("passwd", "target only"),
+            ("JUMP", "bastion"),
+            ("OS", "linux"),
+        ] {
+            cfg.insert(key.to_string(), value.to_string());
+        }
+        let hop = hop_config(&cfg);
+        assert_eq!(hop.get("StrictHostKeyChecking").unwrap(), "yes");
+        assert_eq!(hop.get("IdentityFile").unwrap(), "~/.ssh/deploy");
+        assert_eq!(hop.get("passphrase").unwrap(), "phrase");
+        assert!(hop.get("passwd").is_none());
+        assert!(hop.get("JUMP").is_none(), "a hop must not jump again");
+        assert!(hop.get("OS").is_none());
+    }
+}
@@ -30,3 +30,7 @@ impl From<OpenFlags> for ThisOpenFlags {
 
 #[async_trait]
 impl FileImpl for russh_sftp::client::fs::File {}
+
+/// The stdin/stdout of `cat` when falling back to the shell.
+#[async_trait]
+impl FileImpl for super::shell::ExecFile {}
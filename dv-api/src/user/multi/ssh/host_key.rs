@@ -0,0 +1,247 @@
+use std::path::{Path, PathBuf};
+
+use russh::keys::{self, ssh_key::HashAlg, ssh_key::PublicKey};
+use strum::EnumString;
+use tracing::{info, warn};
+
+use crate::{Error, process::DynInteractor};
+
+use super::{
+    dev::*,
+    options::{HostOptions, expand_path},
+};
+
+/// How to treat a server key which is not recorded in known_hosts,
+/// follows `StrictHostKeyChecking` of ssh_config.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(ascii_case_insensitive)]
+pub enum HostKeyPolicy {
+    #[strum(serialize = "yes")]
+    Reject,
+    #[default]
+    #[strum(serialize = "ask")]
+    Ask,
+    #[strum(serialize = "accept-new", serialize = "no", serialize = "off")]
+    Accept,
+}
+
+impl HostKeyPolicy {
+    pub fn new(options: &HostOptions, cfg: &Config) -> Self {
+        options
+            .lookup(cfg, "StrictHostKeyChecking")
+            .and_then(|p| {
+                p.parse()
+                    .inspect_err(|_| warn!("unknown StrictHostKeyChecking {}", p))
+                    .ok()
+            })
+            .unwrap_or_default()
+    }
+}
+
+pub fn known_hosts_path(options: &HostOptions, cfg: &Config) -> PathBuf {
+    //NOTE:only the first file is used
+    match options
+        .lookup(cfg, "UserKnownHostsFile")
+        .and_then(|files| files.split_whitespace().next())
+    {
+        Some(path) => expand_path(path),
+        None => home::home_dir()
+            .unwrap_or_default()
+            .join(".ssh")
+            .join("known_hosts"),
+    }
+}
+
+/// Decide whether to trust a server key which is not in known_hosts, and record it if so.
+pub async fn trust_new(
+    policy: HostKeyPolicy,
+    interactor: &DynInteractor,
+    host: &str,
+    port: u16,
+    key: &PublicKey,
+    known_hosts: &Path,
+) -> Result<()> {
+    let fingerprint = key.fingerprint(HashAlg::Sha256);
+    let trusted = match policy {
+        HostKeyPolicy::Reject => false,
+        HostKeyPolicy::Accept => true,
+        HostKeyPolicy::Ask => {
+            let hint = format!(
+                "The authenticity of host '{}:{}' can't be established.\n{} key fingerprint is {}.\nTrust it and continue connecting?",
+                host,
+                port,
+                key.algorithm(),
+                fingerprint
+            );
+            interactor.confirm(hint, &["y/yes", "n/no"]).await? == 0
+        }
+    };
+    if !trusted {
+        Err(Error::HostKeyUntrusted(host.to_string()))?
+    }
+    info!("add {} {} to {}", host, fingerprint, known_hosts.display());
+    keys::learn_known_hosts_path(host, port, key, known_hosts)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use russh::client::Handler;
+
+    use super::{super::Client, *};
+    use crate::process::tests::Scripted;
+
+    const KEY: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAIaOpC1ZnsHVphH6m/W9kiVS6N27kHPoLUUQhAj0hGF";
+    const OTHER: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOvDJJqOFnahBHHtUS5a4mmXq32oRBR0EYSpWzuvseE2";
+
+    fn key(key: &str) -> PublicKey {
+        PublicKey::from_openssh(key).unwrap()
+    }
+
+    /// `example.com` hashed by `ssh-keygen -H`, with the salt 1..=20.
+    const HASHED: &str = "|1|AQIDBAUGBwgJCgsMDQ4PEBESExQ=|qvtG0DaqrsqPDhV2Ni+wmYohchA=";
+
+    /// Check `KEY` of `example.com` against `known_hosts`, returns the key left to trust.
+    async fn check(known_hosts: &Path) -> std::result::Result<Option<PublicKey>, Error> {
+        check_port(known_hosts, 22).await
+    }
+
+    async fn check_port(
+        known_hosts: &Path,
+        port: u16,
+    ) -> std::result::Result<Option<PublicKey>, Error> {
+        let unknown = Arc::new(Mutex::new(None));
+        let mut client = Client {
+            host: "example.com".to_string(),
+            port,
+            known_hosts: known_hosts.to_path_buf(),
+            unknown: unknown.clone(),
+        };
+        assert!(client.check_server_key(&key(KEY)).await?);
+        Ok(unknown.lock().unwrap().take())
+    }
+
+    fn untrusted(res: Result<()>) {
+        let e = res.unwrap_err().to_string();
+        assert!(
+            e.contains("host key of example.com is not trusted"),
+            "{}",
+            e
+        );
+    }
+
+    #[tokio::test]
+    async fn trusted_key() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("example.com {}\n", KEY)).unwrap();
+        assert!(check(file.path()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn hashed_entry() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("{} {}\n", HASHED, KEY)).unwrap();
+        assert!(check(file.path()).await.unwrap().is_none());
+        std::fs::write(file.path(), format!("{} {}\n", HASHED, OTHER)).unwrap();
+        let res = check(file.path()).await;
+        assert!(
+            matches!(res, Err(Error::HostKeyChanged { line: 1, .. })),
+            "{:?}",
+            res
+        );
+    }
+
+    #[tokio::test]
+    async fn entry_with_port() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("[example.com]:2222 {}\n", KEY)).unwrap();
+        assert!(check_port(file.path(), 2222).await.unwrap().is_none());
+        //NOTE:the entry is only for that port, like OpenSSH
+        assert_eq!(check(file.path()).await.unwrap(), Some(key(KEY)));
+        std::fs::write(file.path(), format!("example.com {}\n", KEY)).unwrap();
+        assert_eq!(check_port(file.path(), 2222).await.unwrap(), Some(key(KEY)));
+    }
+
+    #[tokio::test]
+    async fn unknown_key() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("other.com {}\n", KEY)).unwrap();
+        assert_eq!(check(file.path()).await.unwrap(), Some(key(KEY)));
+    }
+
+    #[tokio::test]
+    async fn changed_key() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), format!("example.com {}\n", OTHER)).unwrap();
+        let res = check(file.path()).await;
+        assert!(
+            matches!(res, Err(Error::HostKeyChanged { ref host, line: 1 }) if host == "example.com"),
+            "{:?}",
+            res
+        );
+    }
+
+    #[tokio::test]
+    async fn trust_new_writes_back() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let int = Scripted::new(&["n", "y"]);
+        let res = trust_new(
+            HostKeyPolicy::Ask,
+            &int,
+            "example.com",
+            22,
+            &key(KEY),
+            file.path(),
+        )
+        .await;
+        untrusted(res);
+        assert!(check(file.path()).await.unwrap().is_some());
+        let res = trust_new(
+            HostKeyPolicy::Reject,
+            &int,
+            "example.com",
+            22,
+            &key(KEY),
+            file.path(),
+        )
+        .await;
+        untrusted(res);
+        trust_new(
+            HostKeyPolicy::Ask,
+            &int,
+            "example.com",
+            22,
+            &key(KEY),
+            file.path(),
+        )
+        .await
+        .unwrap();
+        assert!(check(file.path()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn accept_new_without_asking() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let int = Scripted::new(&[]);
+        trust_new(
+            HostKeyPolicy::Accept,
+            &int,
+            "example.com",
+            2222,
+            &key(KEY),
+            file.path(),
+        )
+        .await
+        .unwrap();
+        let content = std::fs::read_to_string(file.path()).unwrap();
+        assert!(
+            content.starts_with("[example.com]:2222 ssh-ed25519 "),
+            "{}",
+            content
+        );
+    }
+}
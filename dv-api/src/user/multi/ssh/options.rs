@@ -0,0 +1,249 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use tracing::{debug, warn};
+
+use super::{dev::*, target::Target};
+
+/// Keywords which may be given several times, all values are kept in order.
+const MULTI_VALUED: &[&str] = &["identityfile", "certificatefile"];
+
+/// Options of one host collected from ssh_config, like `ssh -G` does.
+#[derive(Debug, Default)]
+pub struct HostOptions {
+    options: HashMap<String, Vec<String>>,
+}
+
+impl HostOptions {
+    /// Collect the options for `host` from `~/.ssh/config`, a missing file means no options.
+    pub fn parse_home(host: &str) -> Self {
+        let Some(path) = home::home_dir().map(|h| h.join(".ssh").join("config")) else {
+            return Self::default();
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content, host),
+            Err(e) => {
+                debug!("read {} failed: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+    pub fn parse(content: &str, host: &str) -> Self {
+        let mut options: HashMap<String, Vec<String>> = HashMap::new();
+        let mut active = true;
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = split_option(line) else {
+                warn!("invalid ssh_config line: {}", line);
+                continue;
+            };
+            let key = key.to_ascii_lowercase();
+            match key.as_str() {
+                "host" => {
+                    active = match_host(value, host);
+                    continue;
+                }
+                "match" => {
+                    //TODO:support more criteria
+                    active = value.trim().eq_ignore_ascii_case("all");
+                    continue;
+                }
+                _ if !active => continue,
+                _ => {}
+            }
+            let multi = MULTI_VALUED.contains(&key.as_str());
+            let values = options.entry(key).or_default();
+            //NOTE:the first obtained value wins
+            if values.is_empty() || multi {
+                values.push(unquote(value).to_string());
+            }
+        }
+        Self { options }
+    }
+    /// The first value of `key`, which should be lowercase.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options
+            .get(key)
+            .and_then(|v| v.first())
+            .map(|s| s.as_str())
+    }
+    /// All values of `key`, which should be lowercase.
+    pub fn get_all(&self, key: &str) -> &[String] {
+        self.options.get(key).map(|v| v.as_slice()).unwrap_or(&[])
+    }
+    /// Look up a keyword, values in `cfg` take precedence over ssh_config.
+    pub fn lookup<'a>(&'a self, cfg: &'a Config, key: &str) -> Option<&'a str> {
+        cfg.get(key)
+            .map(|s| s.as_str())
+            .or_else(|| self.get(&key.to_ascii_lowercase()))
+    }
+    /// Whether a yes/no keyword is enabled.
+    pub fn enabled(&self, cfg: &Config, key: &str) -> bool {
+        self.lookup(cfg, key)
+            .is_some_and(|v| v.eq_ignore_ascii_case("yes") || v.eq_ignore_ascii_case("true"))
+    }
+    /// A duration given in seconds, `0` or an invalid value means none.
+    pub fn seconds(&self, cfg: &Config, key: &str) -> Option<Duration> {
+        let value = self.lookup(cfg, key)?;
+        match value.parse() {
+            Ok(0) => None,
+            Ok(secs) => Some(Duration::from_secs(secs)),
+            Err(_) => {
+                warn!("invalid {} {}", key, value);
+                None
+            }
+        }
+    }
+    /// The identity files to try in order, the defaults are used when none is configured.
+    pub fn identity_files(&self, cfg: &Config, target: &Target) -> Vec<PathBuf> {
+        let configured = cfg
+            .get("IdentityFile")
+            .into_iter()
+            .chain(self.get_all("identityfile"))
+            .map(|path| expand_path(&target.expand(path)))
+            .collect::<Vec<_>>();
+        if !configured.is_empty() {
+            return configured;
+        }
+        let Some(home) = home::home_dir() else {
+            return configured;
+        };
+        [
+            "id_rsa",
+            "id_ecdsa",
+            "id_ecdsa_sk",
+            "id_ed25519",
+            "id_ed25519_sk",
+        ]
+        .into_iter()
+        .map(|name| home.join(".ssh").join(name))
+        .filter(|path| path.exists())
+        .collect()
+    }
+}
+
+/// Split `Keyword value` or `Keyword=value`.
+fn split_option(line: &str) -> Option<(&str, &str)> {
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let (key, rest) = line.split_at(end);
+    let rest = rest.trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest).trim();
+    (!key.is_empty() && !rest.is_empty()).then_some((key, rest))
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+/// Whether `host` matches a `Host` pattern list, negated patterns veto.
+fn match_host(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace().map(unquote) {
+        if let Some(pattern) = pattern.strip_prefix('!') {
+            if wildcard(pattern.as_bytes(), host.as_bytes()) {
+                return false;
+            }
+        } else if wildcard(pattern.as_bytes(), host.as_bytes()) {
+            matched = true;
+        }
+    }
+    matched
+}
+
+fn wildcard(pattern: &[u8], text: &[u8]) -> bool {
+    match (pattern.first(), text.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            wildcard(&pattern[1..], text) || (!text.is_empty() && wildcard(pattern, &text[1..]))
+        }
+        (Some(b'?'), Some(_)) => wildcard(&pattern[1..], &text[1..]),
+        (Some(p), Some(t)) if p.eq_ignore_ascii_case(t) => wildcard(&pattern[1..], &text[1..]),
+        _ => false,
+    }
+}
+
+/// Expand `%x` tokens like OpenSSH, `%%` is a literal `%`, unknown tokens are kept.
+pub fn expand_tokens(value: &str, tokens: &[(char, &str)]) -> String {
+    let mut expanded = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            expanded.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('%') => expanded.push('%'),
+            Some(t) => match tokens.iter().find(|(k, _)| *k == t) {
+                Some((_, v)) => expanded.push_str(v),
+                None => {
+                    warn!("unknown token %{} in {}", t, value);
+                    expanded.push('%');
+                    expanded.push(t);
+                }
+            },
+            None => expanded.push('%'),
+        }
+    }
+    expanded
+}
+
+/// Expand a leading `~` to the local home directory.
+pub fn expand_path(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), home::home_dir()) {
+        (Some(path), Some(home)) => home.join(path),
+        _ => PathBuf::from(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# comment
+Host bastion
+    HostName 10.0.0.1
+    IdentityFile ~/.ssh/bastion
+
+Host *.internal !db.internal
+    IdentitiesOnly yes
+    IdentityFile ~/.ssh/internal
+
+Host *
+    IdentityFile=~/.ssh/id_ed25519
+    IdentitiesOnly no
+    User "me"
+"#;
+
+    #[test]
+    fn first_value_wins() {
+        let opts = HostOptions::parse(CONFIG, "web.internal");
+        assert_eq!(opts.get("identitiesonly"), Some("yes"));
+        assert_eq!(opts.get("user"), Some("me"));
+        assert_eq!(opts.get("hostname"), None);
+    }
+
+    #[test]
+    fn identity_files_accumulate() {
+        let opts = HostOptions::parse(CONFIG, "web.internal");
+        assert_eq!(
+            opts.get_all("identityfile"),
+            &["~/.ssh/internal", "~/.ssh/id_ed25519"]
+        );
+        let opts = HostOptions::parse(CONFIG, "db.internal");
+        assert_eq!(opts.get_all("identityfile"), &["~/.ssh/id_ed25519"]);
+        assert_eq!(opts.get("identitiesonly"), Some("no"));
+    }
+
+    #[test]
+    fn tokens() {
+        let tokens = [('h', "example.com"), ('p', "22")];
+        assert_eq!(expand_tokens("nc %h %p", &tokens), "nc example.com 22");
+        assert_eq!(expand_tokens("100%% %x", &tokens), "100% %x");
+    }
+}
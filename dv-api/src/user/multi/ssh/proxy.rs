@@ -0,0 +1,80 @@
+use std::{
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+use crate::whatever;
+
+use super::dev::*;
+
+/// The transport of a session over the stdin/stdout of a `ProxyCommand`.
+pub struct ProxyStream {
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl ProxyStream {
+    /// Run `command` through the shell like OpenSSH does, the process is killed on drop.
+    pub fn spawn(command: &str) -> Result<Self> {
+        #[cfg(not(windows))]
+        let mut builder = {
+            let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+            let mut builder = Command::new(shell);
+            builder.arg("-c").arg(format!("exec {}", command));
+            builder
+        };
+        #[cfg(windows)]
+        let mut builder = {
+            let mut builder = Command::new("cmd");
+            builder.arg("/C").arg(command);
+            builder
+        };
+        let mut child = builder
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            whatever!("proxy command {} without stdio", command)
+        };
+        Ok(Self {
+            _child: child,
+            stdin,
+            stdout,
+        })
+    }
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stdin).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stdin).poll_shutdown(cx)
+    }
+}
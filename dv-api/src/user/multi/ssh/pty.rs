@@ -0,0 +1,310 @@
+//! A pty over a session channel. A task pumps the channel, so that the window size and
+//! signals can be sent while someone waits for the exit status.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use russh::{Channel, ChannelMsg, Sig, client};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    sync::{mpsc, watch},
+};
+use tracing::debug;
+
+use super::{dev::*, signal_name};
+
+const BUF_SIZE: usize = 8 * 1024;
+/// Stop taking data from the channel while this much output is not read yet.
+const PENDING_LIMIT: usize = 64 * 1024;
+
+enum Control {
+    WindowChange { cols: u32, rows: u32 },
+    Signal(Sig),
+}
+
+/// `None` while running, then the exit code if the server sent one.
+type Exit = Option<Option<i32>>;
+
+/// Take over a channel which already runs a command with a pty.
+pub fn open(channel: Channel<client::Msg>) -> BoxedPty {
+    spawn(channel)
+}
+
+fn spawn(remote: impl Remote) -> BoxedPty {
+    let (input, pump_input) = tokio::io::duplex(BUF_SIZE);
+    let (pump_output, output) = tokio::io::duplex(BUF_SIZE);
+    let (control, controls) = mpsc::unbounded_channel();
+    let (exit_tx, exit) = watch::channel(None);
+    tokio::spawn(pump(remote, pump_input, pump_output, controls, exit_tx));
+    BoxedPty::new(
+        Ctl {
+            control: control.clone(),
+            exit,
+        },
+        Writer { input, control },
+        Reader(output),
+    )
+}
+
+/// The requests the pump makes of a session channel.
+#[async_trait]
+trait Remote: Send + 'static {
+    async fn wait(&mut self) -> Option<ChannelMsg>;
+    async fn data(&self, data: &[u8]) -> Result<(), russh::Error>;
+    async fn eof(&self) -> Result<(), russh::Error>;
+    async fn control(&self, control: Control) -> Result<(), russh::Error>;
+    async fn close(&self) -> Result<(), russh::Error>;
+}
+
+#[async_trait]
+impl Remote for Channel<client::Msg> {
+    async fn wait(&mut self) -> Option<ChannelMsg> {
+        Channel::wait(self).await
+    }
+    async fn data(&self, data: &[u8]) -> Result<(), russh::Error> {
+        Channel::data(self, data).await
+    }
+    async fn eof(&self) -> Result<(), russh::Error> {
+        Channel::eof(self).await
+    }
+    async fn control(&self, control: Control) -> Result<(), russh::Error> {
+        match control {
+            Control::WindowChange { cols, rows } => self.window_change(cols, rows, 0, 0).await,
+            Control::Signal(sig) => self.signal(sig).await,
+        }
+    }
+    async fn close(&self) -> Result<(), russh::Error> {
+        Channel::close(self).await
+    }
+}
+
+async fn pump(
+    mut channel: impl Remote,
+    mut input: DuplexStream,
+    mut output: DuplexStream,
+    mut controls: mpsc::UnboundedReceiver<Control>,
+    exit: watch::Sender<Exit>,
+) {
+    let mut buf = vec![0; BUF_SIZE];
+    let mut input_open = true;
+    //NOTE:the output waits here for the reader, so that controls are served meanwhile
+    let mut pending = Vec::new();
+    let mut output_open = true;
+    let mut code = None;
+    loop {
+        tokio::select! {
+            msg = channel.wait(), if pending.len() < PENDING_LIMIT => match msg {
+                Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                    //NOTE:keep draining if nobody reads, the exit status comes after the data
+                    if output_open {
+                        pending.extend_from_slice(&data);
+                    }
+                }
+                Some(ChannelMsg::ExitStatus { exit_status }) => code = Some(exit_status as i32),
+                Some(ChannelMsg::ExitSignal { signal_name: sig, .. }) => {
+                    code = code.or(Some(Output::signal_code(&signal_name(sig))));
+                }
+                Some(_) => {}
+                None => break,
+            },
+            n = output.write(&pending), if !pending.is_empty() => match n {
+                Ok(n) => {
+                    pending.drain(..n);
+                }
+                Err(e) => {
+                    debug!("pty output dropped: {}", e);
+                    output_open = false;
+                    pending.clear();
+                }
+            },
+            n = input.read(&mut buf), if input_open => match n {
+                Ok(0) | Err(_) => {
+                    input_open = false;
+                    if let Err(e) = channel.eof().await {
+                        debug!("send eof failed: {}", e);
+                    }
+                }
+                Ok(n) => {
+                    if let Err(e) = channel.data(&buf[..n]).await {
+                        debug!("send data failed: {}", e);
+                    }
+                }
+            },
+            control = controls.recv() => {
+                let res = match control {
+                    Some(control) => channel.control(control).await,
+                    //NOTE:nobody can wait or write any more, hang up
+                    None => {
+                        if let Err(e) = channel.close().await {
+                            debug!("close channel failed: {}", e);
+                        }
+                        break;
+                    }
+                };
+                if let Err(e) = res {
+                    debug!("send control failed: {}", e);
+                }
+            },
+        }
+    }
+    if output_open && let Err(e) = output.write_all(&pending).await {
+        debug!("pty output dropped: {}", e);
+    }
+    exit.send_replace(Some(code));
+}
+
+fn no_status() -> io::Error {
+    io::Error::other("channel closed without exit status")
+}
+
+struct Ctl {
+    control: mpsc::UnboundedSender<Control>,
+    exit: watch::Receiver<Exit>,
+}
+
+#[async_trait]
+impl PtyCtl for Ctl {
+    async fn wait(&mut self) -> e4pty::Result<i32> {
+        let exit = match self.exit.wait_for(|exit| exit.is_some()).await {
+            Ok(exit) => *exit,
+            Err(_) => None,
+        };
+        match exit {
+            Some(Some(code)) => Ok(code),
+            _ => Err(no_status())?,
+        }
+    }
+    async fn try_wait(&mut self) -> e4pty::Result<Option<i32>> {
+        let exit = *self.exit.borrow();
+        match exit {
+            None => Ok(None),
+            Some(Some(code)) => Ok(Some(code)),
+            Some(None) => Err(no_status())?,
+        }
+    }
+    async fn signal(&mut self, sig: Signal) -> e4pty::Result<()> {
+        let sig = match sig {
+            Signal::Hup => Sig::HUP,
+            Signal::Int => Sig::INT,
+            Signal::Kill => Sig::KILL,
+            Signal::Term => Sig::TERM,
+        };
+        if self.control.send(Control::Signal(sig)).is_err() {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))?
+        }
+        Ok(())
+    }
+}
+
+struct Writer {
+    input: DuplexStream,
+    control: mpsc::UnboundedSender<Control>,
+}
+
+impl AsyncWrite for Writer {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.input).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.input).poll_flush(cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.input).poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl PtyWriter for Writer {
+    async fn window_change(&self, width: u16, height: u16) -> e4pty::Result<()> {
+        let control = Control::WindowChange {
+            cols: width as u32,
+            rows: height as u32,
+        };
+        if self.control.send(control).is_err() {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))?
+        }
+        Ok(())
+    }
+}
+
+struct Reader(DuplexStream);
+
+impl AsyncRead for Reader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl PtyReader for Reader {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use russh::{ChannelMsg, CryptoVec, Sig};
+    use tokio::sync::mpsc;
+
+    use super::{Control, Remote, spawn};
+    use crate::process::Signal;
+
+    /// A channel fed by the test, which reports the signals it is asked to send.
+    struct Fake {
+        msgs: mpsc::UnboundedReceiver<ChannelMsg>,
+        signals: mpsc::UnboundedSender<Sig>,
+    }
+
+    #[async_trait::async_trait]
+    impl Remote for Fake {
+        async fn wait(&mut self) -> Option<ChannelMsg> {
+            self.msgs.recv().await
+        }
+        async fn data(&self, _: &[u8]) -> Result<(), russh::Error> {
+            Ok(())
+        }
+        async fn eof(&self) -> Result<(), russh::Error> {
+            Ok(())
+        }
+        async fn control(&self, control: Control) -> Result<(), russh::Error> {
+            if let Control::Signal(sig) = control {
+                let _ = self.signals.send(sig);
+            }
+            Ok(())
+        }
+        async fn close(&self) -> Result<(), russh::Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn signal_while_output_unread() {
+        let (msg_tx, msgs) = mpsc::unbounded_channel();
+        let (signals, mut sent) = mpsc::unbounded_channel();
+        let pty = spawn(Fake { msgs, signals });
+        let (mut ctl, _writer, _reader) = pty.destruct();
+        let chunk = CryptoVec::from_slice(&[b'x'; 4096]);
+        for _ in 0..64 {
+            msg_tx
+                .send(ChannelMsg::Data {
+                    data: chunk.clone(),
+                })
+                .unwrap();
+        }
+        ctl.signal(Signal::Term).await.unwrap();
+        let sig = tokio::time::timeout(Duration::from_secs(1), sent.recv())
+            .await
+            .expect("signal stalled behind unread output");
+        assert_eq!(sig, Some(Sig::TERM));
+    }
+}
@@ -0,0 +1,430 @@
+//! File operations over exec channels, for servers without the sftp subsystem,
+//! e.g. minimal dropbear or busybox images.
+
+use std::{
+    io::{self, ErrorKind},
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use e4pty::quote;
+use russh::{
+    Channel, ChannelMsg,
+    client::{Handle, Msg},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    sync::oneshot,
+};
+use tracing::debug;
+
+use crate::whatever;
+
+use super::{Client, dev::*};
+
+/// size, uid, gid, raw mode in hex, atime, mtime
+const STAT_FORMAT: &str = "%s %u %g %f %X %Y";
+
+//NOTE:exit codes of the checks below, chosen to not collide with the commands they guard
+const NOT_FOUND: u32 = 44;
+const EXISTS: u32 = 45;
+const DENIED: u32 = 46;
+const NOT_DIR: u32 = 47;
+
+/// Run `script` and collect its stdout and exit status.
+async fn run(h: &Handle<Client>, script: &str) -> Result<(u32, Vec<u8>)> {
+    let mut channel = h.channel_open_session().await?;
+    debug!("run {}", script);
+    channel.exec(true, script).await?;
+    let mut stdout = Vec::new();
+    let mut code = None;
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::Data { data } => stdout.extend_from_slice(&data),
+            ChannelMsg::ExitStatus { exit_status } => code = Some(exit_status),
+            _ => {}
+        }
+    }
+    let Some(code) = code else {
+        whatever!("{} exited without status", script)
+    };
+    Ok((code, stdout))
+}
+
+/// Turn the exit code of a check into an io error, so that `is_not_found` works as with sftp.
+fn check(code: u32, path: &str, op: &str) -> Result<()> {
+    let kind = match code {
+        0 => return Ok(()),
+        NOT_FOUND => ErrorKind::NotFound,
+        EXISTS => ErrorKind::AlreadyExists,
+        DENIED => ErrorKind::PermissionDenied,
+        NOT_DIR => ErrorKind::NotADirectory,
+        _ => whatever!("{} {} failed with {}", op, path, code),
+    };
+    Err(std::io::Error::new(kind, format!("{} {}", op, path)))?
+}
+
+/// Parse a line of `STAT_FORMAT`, optionally followed by the name.
+fn parse_stat(line: &str) -> Option<(FileAttributes, &str)> {
+    let mut fields = line.splitn(7, ' ');
+    let attr = FileAttributes {
+        size: Some(fields.next()?.parse().ok()?),
+        uid: Some(fields.next()?.parse().ok()?),
+        gid: Some(fields.next()?.parse().ok()?),
+        permissions: Some(u32::from_str_radix(fields.next()?, 16).ok()?),
+        atime: Some(fields.next()?.parse().ok()?),
+        mtime: Some(fields.next()?.parse().ok()?),
+        ..Default::default()
+    };
+    Some((attr, fields.next().unwrap_or_default()))
+}
+
+pub async fn stat(h: &Handle<Client>, path: &str) -> Result<FileAttributes> {
+    let p = quote::sh(path);
+    let script = format!("test -e {p} || exit {NOT_FOUND}; stat -L -c '{STAT_FORMAT}' -- {p}");
+    let (code, stdout) = run(h, &script).await?;
+    check(code, path, "stat")?;
+    let stdout = String::from_utf8_lossy(&stdout);
+    match parse_stat(stdout.trim_end()) {
+        Some((attr, _)) => Ok(attr),
+        None => whatever!("unexpected stat output {}", stdout),
+    }
+}
+
+/// List the regular files under `path` like `glob_file_meta`, paths are relative to it.
+pub async fn walk(h: &Handle<Client>, path: &str, ignore: &Ignore) -> Result<Vec<Metadata>> {
+    let p = quote::sh(path);
+    let prune = prune_clause(ignore);
+    let script = format!(
+        "test -e {p} || exit {NOT_FOUND}; test -d {p} || exit {NOT_DIR}; cd {p} && find . {prune}-type f -exec stat -c '{STAT_FORMAT} %n' {{}} +"
+    );
+    let (code, stdout) = run(h, &script).await?;
+    check(code, path, "walk")?;
+    let stdout = String::from_utf8_lossy(&stdout);
+    let mut infos = Vec::new();
+    for line in stdout.lines() {
+        let Some((attr, name)) = parse_stat(line) else {
+            whatever!("unexpected stat output {}", line)
+        };
+        //NOTE:only the plain directories are pruned, the other patterns are matched here
+        let name = name.strip_prefix("./").unwrap_or(name);
+        if ignore.is_ignored_in(name) {
+            continue;
+        }
+        infos.push(Metadata {
+            path: name.to_string().into(),
+            attr,
+        });
+    }
+    Ok(infos)
+}
+
+/// The `find` tests skipping the directories `ignore` excludes as a whole, if any.
+fn prune_clause(ignore: &Ignore) -> String {
+    let tests: Vec<_> = ignore
+        .prunable()
+        .into_iter()
+        .map(|prune| match prune {
+            Prune::Path(path) => format!("-path {}", quote::sh(&format!("./{}", path))),
+            Prune::Name(name) => format!("-name {}", quote::sh(name)),
+        })
+        .collect();
+    if tests.is_empty() {
+        return String::new();
+    }
+    format!("\\( {} \\) -type d -prune -o ", tests.join(" -o "))
+}
+
+/// Open a file as the stdin or stdout of `cat`, missing parents are created like the sftp path does.
+/// Writes are only complete once the file is shut down, which fails if `cat` did.
+pub async fn open(
+    h: &Handle<Client>,
+    path: &str,
+    flags: OpenFlags,
+    attr: FileAttributes,
+) -> Result<BoxedFile> {
+    let p = quote::sh(path);
+    let write = flags.intersects(OpenFlags::WRITE | OpenFlags::APPEND);
+    let mut checks = Vec::new();
+    if !write {
+        checks.push(format!("test -e {p} || exit {NOT_FOUND}"));
+        checks.push(format!("test -r {p} || exit {DENIED}"));
+    } else {
+        if !flags.contains(OpenFlags::CREATE) {
+            checks.push(format!("test -e {p} || exit {NOT_FOUND}"));
+        } else if let Some((parent, _)) = path.rsplit_once('/')
+            && !parent.is_empty()
+        {
+            checks.push(format!(
+                "mkdir -p -- {} || exit {DENIED}",
+                quote::sh(parent)
+            ));
+        }
+        if flags.contains(OpenFlags::EXCLUDE) {
+            checks.push(format!("test ! -e {p} || exit {EXISTS}"));
+        }
+        checks.push(format!("test ! -e {p} || test -w {p} || exit {DENIED}"));
+    }
+    let (code, _) = run(h, &checks.join("; ")).await?;
+    check(code, path, "open")?;
+
+    let cmd = if !write {
+        format!("exec cat -- {p}")
+    } else {
+        let redirect = if flags.contains(OpenFlags::APPEND) {
+            ">>"
+        } else if flags.contains(OpenFlags::TRUNCATE) {
+            ">"
+        } else {
+            "1<>"
+        };
+        match attr.permissions {
+            //NOTE:like sftp, the permissions only apply to a newly created file
+            Some(mode) => format!("umask {:03o}; exec cat {redirect} {p}", !mode & 0o777),
+            None => format!("exec cat {redirect} {p}"),
+        }
+    };
+    let channel = h.channel_open_session().await?;
+    debug!("open {}", cmd);
+    channel.exec(true, cmd).await?;
+    Ok(Box::new(ExecFile::spawn(channel, path)))
+}
+
+const BUF_SIZE: usize = 8 * 1024;
+
+/// The stdin/stdout of a command behind a task pumping its channel, so that the exit status
+/// is still received after the stream is shut down.
+pub struct ExecFile {
+    stream: DuplexStream,
+    exit: Option<oneshot::Receiver<Option<u32>>>,
+    path: String,
+}
+
+impl ExecFile {
+    fn spawn(channel: Channel<Msg>, path: &str) -> Self {
+        let (stream, pump_stream) = tokio::io::duplex(BUF_SIZE);
+        let (exit_tx, exit) = oneshot::channel();
+        tokio::spawn(pump(channel, pump_stream, exit_tx));
+        Self::new(stream, exit, path)
+    }
+    fn new(stream: DuplexStream, exit: oneshot::Receiver<Option<u32>>, path: &str) -> Self {
+        Self {
+            stream,
+            exit: Some(exit),
+            path: path.to_string(),
+        }
+    }
+    /// Wait for the exit status once the command is done with the stream, it is checked only once.
+    fn poll_exit(&mut self, cx: &mut Context<'_>, op: &str) -> Poll<io::Result<()>> {
+        let Some(exit) = self.exit.as_mut() else {
+            return Poll::Ready(Ok(()));
+        };
+        let code = ready!(Pin::new(exit).poll(cx));
+        self.exit = None;
+        Poll::Ready(match code {
+            Ok(Some(0)) => Ok(()),
+            Ok(Some(NOT_FOUND)) => Err(io::Error::new(ErrorKind::NotFound, self.path.clone())),
+            Ok(Some(code)) => Err(io::Error::other(format!(
+                "{} {} failed with {}",
+                op, self.path, code
+            ))),
+            _ => Err(io::Error::other(format!(
+                "{} {} closed without exit status",
+                op, self.path
+            ))),
+        })
+    }
+}
+
+async fn pump(
+    mut channel: Channel<Msg>,
+    mut stream: DuplexStream,
+    exit: oneshot::Sender<Option<u32>>,
+) {
+    let mut buf = vec![0; BUF_SIZE];
+    let mut input_open = true;
+    let mut code = None;
+    loop {
+        tokio::select! {
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) => {
+                    if let Err(e) = stream.write_all(&data).await {
+                        debug!("file content dropped: {}", e);
+                    }
+                }
+                Some(ChannelMsg::ExitStatus { exit_status }) => code = Some(exit_status),
+                Some(_) => {}
+                None => break,
+            },
+            n = stream.read(&mut buf), if input_open => match n {
+                Ok(0) | Err(_) => {
+                    input_open = false;
+                    if let Err(e) = channel.eof().await {
+                        debug!("send eof failed: {}", e);
+                    }
+                }
+                Ok(n) => {
+                    if let Err(e) = channel.data(&buf[..n]).await {
+                        debug!("send data failed: {}", e);
+                    }
+                }
+            },
+        }
+    }
+    let _ = exit.send(code);
+}
+
+impl AsyncRead for ExecFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.stream).poll_read(cx, buf))?;
+        //NOTE:EOF, which is only genuine if the command succeeded
+        if buf.filled().len() == filled && buf.remaining() > 0 {
+            return self.poll_exit(cx, "read");
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for ExecFile {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+    /// Send EOF, then wait for the command to exit.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.stream).poll_shutdown(cx))?;
+        self.poll_exit(cx, "write")
+    }
+}
+
+/// Create `dir` like `tmp::prepare` does, and remove the files named `pattern` older than a day.
+pub async fn private_dir(h: &Handle<Client>, dir: &str, pattern: &str) -> Result<()> {
+    let d = quote::sh(dir);
+    let script = format!(
+        "mkdir -p -m 700 -- {d} 2>/dev/null; test ! -L {d} && test -d {d} || exit {NOT_DIR}; chmod 700 -- {d} || exit {DENIED}; find {d} -maxdepth 1 -type f -name {} -mtime +0 -exec rm -f {{}} +",
+        quote::sh(pattern)
+    );
+    let (code, _) = run(h, &script).await?;
+    check(code, dir, "prepare")
+}
+
+/// Remove the files in `dir` matching `pattern` which were not modified for a day.
+pub async fn sweep(h: &Handle<Client>, dir: &str, pattern: &str) -> Result<()> {
+    let script = format!(
+        "find {} -maxdepth 1 -type f -name {} -mtime +0 -exec rm -f {{}} +",
+        quote::sh(dir),
+        quote::sh(pattern)
+    );
+    let (code, _) = run(h, &script).await?;
+    check(code, dir, "sweep")
+}
+
+pub async fn remove(h: &Handle<Client>, path: &str) -> Result<()> {
+    let p = quote::sh(path);
+    let (code, _) = run(h, &format!("test -e {p} || exit {NOT_FOUND}; rm -f -- {p}")).await?;
+    check(code, path, "remove")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// Stands in for the remote `cat`, collecting what it reads until EOF and exiting with `code`.
+    fn cat(code: u32) -> (ExecFile, Arc<Mutex<Vec<u8>>>) {
+        let (stream, mut remote) = tokio::io::duplex(16);
+        let (exit_tx, exit) = oneshot::channel();
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let content = written.clone();
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            remote.read_to_end(&mut buf).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            *content.lock().unwrap() = buf;
+            exit_tx.send(Some(code)).unwrap();
+        });
+        (ExecFile::new(stream, exit, "/tmp/f"), written)
+    }
+
+    /// Stands in for the remote `cat` of a file, sending `content` and exiting with `code`.
+    fn cat_out(content: &'static [u8], code: u32) -> ExecFile {
+        let (stream, mut remote) = tokio::io::duplex(16);
+        let (exit_tx, exit) = oneshot::channel();
+        tokio::spawn(async move {
+            remote.write_all(content).await.unwrap();
+            exit_tx.send(Some(code)).unwrap();
+        });
+        ExecFile::new(stream, exit, "/tmp/f")
+    }
+
+    #[tokio::test]
+    async fn read_checks_exit() {
+        let mut content = Vec::new();
+        cat_out(b"data", 0).read_to_end(&mut content).await.unwrap();
+        assert_eq!(content, b"data");
+        let mut content = Vec::new();
+        let e = cat_out(b"partial", 1)
+            .read_to_end(&mut content)
+            .await
+            .unwrap_err();
+        assert!(e.to_string().contains("read /tmp/f failed"), "{}", e);
+        let e = cat_out(b"", NOT_FOUND)
+            .read_to_end(&mut Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::NotFound);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_exit() {
+        let (mut file, written) = cat(0);
+        file.write_all(&[b'x'; 100]).await.unwrap();
+        file.shutdown().await.unwrap();
+        //NOTE:a script uploaded like this runs right after, so it must be complete by now
+        assert_eq!(*written.lock().unwrap(), [b'x'; 100]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_reports_failure() {
+        let (mut file, _remote) = cat(1);
+        file.write_all(b"x").await.unwrap();
+        let e = file.shutdown().await.unwrap_err();
+        assert!(e.to_string().contains("/tmp/f"), "{}", e);
+    }
+
+    #[test]
+    fn stat_line() {
+        let (attr, name) = parse_stat("12 1000 100 81a4 1700000000 1700000001 ./a b").unwrap();
+        assert_eq!(attr.size, Some(12));
+        assert_eq!(attr.permissions, Some(0o100644));
+        assert_eq!(attr.mtime, Some(1700000001));
+        assert!(!attr.is_dir());
+        assert_eq!(name, "./a b");
+        assert!(parse_stat("12 1000").is_none());
+    }
+
+    #[test]
+    fn prune_plain_dirs() {
+        assert_eq!(prune_clause(&Ignore::default()), "");
+        let ignore = Ignore::new(["build/", "/my dir", "*.o"]).unwrap();
+        assert_eq!(
+            prune_clause(&ignore),
+            r"\( -name build -o -path './my dir' \) -type d -prune -o "
+        );
+    }
+}
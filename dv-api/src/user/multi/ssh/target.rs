@@ -0,0 +1,147 @@
+use crate::whatever;
+
+use super::{
+    dev::*,
+    options::{HostOptions, expand_tokens},
+};
+
+/// The address and login of a host, resolved from ssh_config.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// the name given by the user, used to look up ssh_config
+    pub alias: String,
+    pub host_name: String,
+    pub port: u16,
+    pub user: String,
+}
+
+impl Target {
+    pub fn resolve(alias: &str) -> Result<(Self, HostOptions)> {
+        Self::resolve_with(alias, None, HostOptions::parse_home(alias))
+    }
+    fn resolve_with(
+        alias: &str,
+        user: Option<&str>,
+        options: HostOptions,
+    ) -> Result<(Self, HostOptions)> {
+        let host_name = options
+            .get("hostname")
+            .map(|h| expand_tokens(h, &[('h', alias)]))
+            .unwrap_or_else(|| alias.to_string());
+        let port = match options.get("port") {
+            Some(port) => match port.parse() {
+                Ok(port) => port,
+                Err(_) => whatever!("invalid port {} of {}", port, alias),
+            },
+            None => 22,
+        };
+        let user = match user.or_else(|| options.get("user")) {
+            Some(user) => user.to_string(),
+            None => match std::env::var("USER").or_else(|_| std::env::var("USERNAME")) {
+                Ok(user) => user,
+                Err(_) => whatever!("unknown user of {}", alias),
+            },
+        };
+        Ok((
+            Self {
+                alias: alias.to_string(),
+                host_name,
+                port,
+                user,
+            },
+            options,
+        ))
+    }
+    /// Expand the `%x` tokens of ssh_config about this target, e.g. in `ProxyCommand`.
+    pub fn expand(&self, value: &str) -> String {
+        let home = home::home_dir()
+            .map(|h| h.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let port = self.port.to_string();
+        expand_tokens(
+            value,
+            &[
+                ('d', home.as_str()),
+                ('h', self.host_name.as_str()),
+                ('n', self.alias.as_str()),
+                ('p', port.as_str()),
+                ('r', self.user.as_str()),
+            ],
+        )
+    }
+    /// Resolve one hop of `ProxyJump`, in the form of `[user@]host[:port]`.
+    pub fn hop(hop: &str) -> Result<(Self, HostOptions)> {
+        Self::hop_with(hop, HostOptions::parse_home)
+    }
+    /// Like `hop`, with the options of a host given by `options`.
+    fn hop_with(
+        hop: &str,
+        options: impl FnOnce(&str) -> HostOptions,
+    ) -> Result<(Self, HostOptions)> {
+        let hop = hop.trim();
+        let hop = hop.strip_prefix("ssh://").unwrap_or(hop);
+        let (user, rest) = match hop.rsplit_once('@') {
+            Some((user, rest)) => (Some(user), rest),
+            None => (None, hop),
+        };
+        let (host, port) = if let Some(rest) = rest.strip_prefix('[') {
+            let Some((host, port)) = rest.split_once(']') else {
+                whatever!("invalid jump host {}", hop)
+            };
+            (host, port.strip_prefix(':'))
+        } else {
+            match rest.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (rest, None),
+            }
+        };
+        let (mut target, options) = Self::resolve_with(host, user, options(host))?;
+        if let Some(port) = port {
+            let Ok(port) = port.parse() else {
+                whatever!("invalid port of jump host {}", hop)
+            };
+            target.port = port;
+        }
+        Ok((target, options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_proxy_command() {
+        let target = Target {
+            alias: "web".to_string(),
+            host_name: "10.0.0.2".to_string(),
+            port: 2222,
+            user: "me".to_string(),
+        };
+        assert_eq!(
+            target.expand("socat - TCP:%h:%p,%%n=%n,%r"),
+            "socat - TCP:10.0.0.2:2222,%n=web,me"
+        );
+    }
+
+    #[test]
+    fn parse_hop() {
+        const CONFIG: &str = "Host jump\n  HostName jump.example.com\n  User carol\n  Port 2200\n";
+        let options = |host: &str| HostOptions::parse(CONFIG, host);
+        let (t, _) = Target::hop_with("alice@jump:2222", options).unwrap();
+        assert_eq!(
+            (t.user.as_str(), t.host_name.as_str(), t.port),
+            ("alice", "jump.example.com", 2222)
+        );
+        let (t, _) = Target::hop_with("jump", options).unwrap();
+        assert_eq!(
+            (t.user.as_str(), t.host_name.as_str(), t.port),
+            ("carol", "jump.example.com", 2200)
+        );
+        let (t, _) = Target::hop_with("bob@[::1]:22", options).unwrap();
+        assert_eq!(
+            (t.user.as_str(), t.host_name.as_str(), t.port),
+            ("bob", "::1", 22)
+        );
+    }
+}
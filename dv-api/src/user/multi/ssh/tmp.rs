@@ -0,0 +1,192 @@
+//! The private directory where scripts are uploaded before they run.
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tracing::{debug, warn};
+
+use crate::whatever;
+
+use super::{Conn, SSHSession, dev::*, shell};
+
+/// Uploaded scripts are named `script-XXXXXX` plus the suffix of the executor.
+const PREFIX: &str = "script-";
+/// Scripts uploaded to the home directory are hidden and named apart from other files there.
+const HOME_PREFIX: &str = ".dv-script-";
+/// Scripts older than this are left by earlier runs which could not clean up.
+const STALE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Locate and prepare `<base>/dv-<user>`, the base is `TMPDIR` of the config or the server.
+/// Scripts go to the home directory if the default one can't be used, after sweeping the
+/// ones left there by earlier runs.
+pub async fn locate(
+    session: &SSHSession,
+    base: Option<&str>,
+    user: &str,
+    os: &Os,
+) -> Result<Option<String>> {
+    let configured = base.is_some();
+    let conn = session.conn().await?;
+    let base = match base {
+        Some(base) => session.canonicalize(base)?.into_owned(),
+        //TODO:find the temp directory of windows, the environment is not detected there
+        None if os.is_windows() => return fall_back(&conn).await,
+        None => session
+            .env
+            .get("TMPDIR")
+            .map(|s| s.as_str())
+            .unwrap_or("/tmp")
+            .to_string(),
+    };
+    let dir = format!("{}/dv-{}", base.trim_end_matches('/'), user);
+    match prepare(&conn, &dir).await {
+        Ok(()) => Ok(Some(dir)),
+        Err(e) if !configured => {
+            warn!("cannot put scripts in {}, fall back to home: {}", dir, e);
+            fall_back(&conn).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Use the home directory for scripts, sweeping those left there before.
+async fn fall_back(conn: &Conn) -> Result<Option<String>> {
+    if let Err(e) = sweep(conn, ".", HOME_PREFIX).await {
+        warn!("sweep stale scripts in home failed: {}", e);
+    }
+    Ok(None)
+}
+
+/// Create `dir` readable only by the user, and sweep the scripts left by earlier runs.
+pub async fn prepare(conn: &Conn, dir: &str) -> Result<()> {
+    let Some(sftp) = &conn.sftp else {
+        return shell::private_dir(&conn.session, dir, &format!("{}*", PREFIX)).await;
+    };
+    if let Err(e) = sftp.create_dir(dir).await {
+        debug!("create {} failed: {}", dir, e);
+    }
+    //NOTE:a symlink planted by someone else would redirect the mode change and the sweep
+    check_private(dir, &sftp.symlink_metadata(dir).await?)?;
+    //NOTE:only the owner may change the mode, so a directory taken by someone else is refused
+    let attr = FileAttributes {
+        permissions: Some(0o700),
+        ..Default::default()
+    };
+    sftp.set_metadata(dir, attr).await?;
+    sweep(conn, dir, PREFIX).await
+}
+
+/// Refuse anything but a real directory, `attr` must not follow symlinks.
+fn check_private(dir: &str, attr: &FileAttributes) -> Result<()> {
+    if attr.is_symlink() {
+        whatever!("{} is a symlink", dir)
+    }
+    if !attr.is_dir() {
+        whatever!("{} is not a directory", dir)
+    }
+    Ok(())
+}
+
+/// Remove the scripts named `prefix*` in `dir` which were left for a day.
+async fn sweep(conn: &Conn, dir: &str, prefix: &str) -> Result<()> {
+    let Some(sftp) = &conn.sftp else {
+        return shell::sweep(&conn.session, dir, &format!("{}*", prefix)).await;
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    for entry in sftp.read_dir(dir).await? {
+        let name = entry.file_name();
+        let stale = entry
+            .metadata()
+            .mtime
+            .is_some_and(|mtime| Duration::from_secs(mtime as u64) + STALE < now);
+        if !name.starts_with(prefix) || !stale {
+            continue;
+        }
+        let path = format!("{}/{}", dir, name);
+        debug!("remove stale script {}", path);
+        if let Err(e) = sftp.remove_file(&path).await {
+            warn!("remove stale script {} failed: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+/// A name for a new script in `dir`, or relative to the home directory without one.
+pub fn script_name(dir: Option<&str>, suffix: &str) -> String {
+    let mut name = match dir {
+        Some(dir) => format!("{}/{}", dir, PREFIX),
+        None => HOME_PREFIX.to_string(),
+    };
+    name.extend(std::iter::repeat_with(fastrand::alphanumeric).take(6));
+    name.push_str(suffix);
+    name
+}
+
+/// An uploaded script, removed once dropped in case it could not remove itself,
+/// e.g. when the interpreter failed to start.
+pub struct ScriptFile {
+    conn: Arc<Conn>,
+    path: String,
+}
+
+impl ScriptFile {
+    pub fn new(conn: Arc<Conn>, path: String) -> Self {
+        Self { conn, path }
+    }
+}
+
+impl Drop for ScriptFile {
+    fn drop(&mut self) {
+        let Ok(rt) = tokio::runtime::Handle::try_current() else {
+            warn!("script {} left behind", self.path);
+            return;
+        };
+        let conn = self.conn.clone();
+        let path = std::mem::take(&mut self.path);
+        rt.spawn(async move {
+            let res: Result<()> = match &conn.sftp {
+                Some(sftp) => sftp.remove_file(&path).await.map_err(|e| e.into()),
+                None => shell::remove(&conn.session, &path).await,
+            };
+            match res {
+                Ok(_) => debug!("removed script {}", path),
+                //NOTE:usually the script has removed itself
+                Err(e) if e.is_not_found() => {}
+                Err(e) => warn!("remove script {} failed: {}", path, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_names() {
+        let name = script_name(Some("/tmp/dv-me"), ".sh");
+        assert!(name.starts_with("/tmp/dv-me/script-") && name.ends_with(".sh"));
+        assert_eq!(name.len(), "/tmp/dv-me/script-XXXXXX.sh".len());
+        //NOTE:the home directory is swept by this prefix, it must stay apart from other files
+        let name = script_name(None, ".py");
+        assert!(name.starts_with(".dv-script-") && name.ends_with(".py"));
+        assert_eq!(name.len(), ".dv-script-XXXXXX.py".len());
+    }
+
+    #[test]
+    fn private_dir_refuses_symlink() {
+        let attr = |mode| FileAttributes {
+            permissions: Some(mode),
+            ..Default::default()
+        };
+        assert!(check_private("/tmp/dv-me", &attr(0o040700)).is_ok());
+        let e = check_private("/tmp/dv-me", &attr(0o120777)).unwrap_err();
+        assert!(e.to_string().contains("is a symlink"), "{}", e);
+        let e = check_private("/tmp/dv-me", &attr(0o100600)).unwrap_err();
+        assert!(e.to_string().contains("is not a directory"), "{}", e);
+    }
+}
@@ -5,8 +5,9 @@ use super::dev::*;
 pub async fn detect(u: &BoxedUser) -> Result<Pm> {
     debug!("try to detect manjaro package manager");
     let ec = u
-        .exec(Script::sh(Box::new(
-            [r#"echo yes
+        .exec(
+            Script::sh(Box::new(
+                [r#"echo yes
 if command -v yay >/dev/null 2>&1; then
     exit 1
 elif command -v paru >/dev/null 2>&1; then
@@ -15,8 +16,10 @@ else
     exit 0
 fi
 "#]
-            .into_iter(),
-        )))
+                .into_iter(),
+            )),
+            &ExecOptions::default(),
+        )
         .wait()
         .await?;
 
@@ -9,22 +9,39 @@ pub struct SqliteCache {
     conn: Mutex<rusqlite::Connection>,
 }
 
+/// `cache` keeps the mtimes of both sides at the last copy, `digest` the hash of the content
+/// copied then, and `base` the content itself, as the base of a later merge.
+fn init(conn: &rusqlite::Connection) {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS cache (
+            device TEXT NOT NULL,
+            path TEXT NOT NULL,
+            version INTEGER NOT NULL,
+            lastest INTEGER NOT NULL,
+            PRIMARY KEY (device, path)
+        );
+        CREATE TABLE IF NOT EXISTS digest (
+            device TEXT NOT NULL,
+            path TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            PRIMARY KEY (device, path)
+        );
+        CREATE TABLE IF NOT EXISTS base (
+            device TEXT NOT NULL,
+            path TEXT NOT NULL,
+            content BLOB NOT NULL,
+            PRIMARY KEY (device, path)
+        );",
+    )
+    .expect("create initial table");
+}
+
 impl SqliteCache {
     pub fn new(db_path: impl AsRef<Path>) -> Self {
         let db_path = db_path.as_ref();
         info!("use sqlite path {}", db_path.display());
         let conn = rusqlite::Connection::open(db_path).expect("open sqlite connection");
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS cache (
-                device TEXT NOT NULL,
-                path TEXT NOT NULL,
-                version INTEGER NOT NULL,
-                lastest INTEGER NOT NULL,
-                PRIMARY KEY (device, path)
-            )",
-            [],
-        )
-        .expect("create initial table");
+        init(&conn);
         Self {
             conn: Mutex::new(conn),
         }
@@ -32,17 +49,7 @@ impl SqliteCache {
     #[cfg(test)]
     pub fn memory() -> Self {
         let conn = rusqlite::Connection::open_in_memory().expect("open sqlite connection");
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS cache (
-                device TEXT NOT NULL,
-                path TEXT NOT NULL,
-                version INTEGER NOT NULL,
-                lastest INTEGER NOT NULL,
-                PRIMARY KEY (device, path)
-            )",
-            [],
-        )
-        .expect("create initial table");
+        init(&conn);
         Self {
             conn: Mutex::new(conn),
         }
@@ -73,18 +80,75 @@ impl SqliteCache {
             )
             .map(|_| ())
     }
-    pub async fn del(&self, uid: &str, path: &str) -> Result<()> {
-        info!("cache del: {} {}", uid, path);
-        let conn = self.conn.lock().await;
-        if !path.is_empty() {
-            conn.execute(
-                "DELETE FROM cache WHERE device = ? AND path = ?",
+    pub async fn get_digest(&self, uid: &str, path: &str) -> Result<Option<String>> {
+        let row = self.conn.lock().await.query_row(
+            "SELECT hash FROM digest WHERE device = ? AND path = ?",
+            [uid, path],
+            |row| row.get(0),
+        );
+        match row {
+            Ok(hash) => Ok(Some(hash)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    pub async fn set_digest(&self, uid: &str, path: &str, hash: &str) -> Result<()> {
+        info!("digest set: {} {} {}", uid, path, hash);
+        self.conn
+            .lock()
+            .await
+            .execute(
+                "INSERT OR REPLACE INTO digest (device, path, hash) VALUES (?, ?, ?)",
+                [uid, path, hash],
+            )
+            .map(|_| ())
+    }
+    pub async fn get_base(&self, uid: &str, path: &str) -> Result<Option<Vec<u8>>> {
+        let row = self.conn.lock().await.query_row(
+            "SELECT content FROM base WHERE device = ? AND path = ?",
+            [uid, path],
+            |row| row.get(0),
+        );
+        match row {
+            Ok(content) => Ok(Some(content)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    pub async fn set_base(&self, uid: &str, path: &str, content: &[u8]) -> Result<()> {
+        info!("base set: {} {} {} bytes", uid, path, content.len());
+        self.conn
+            .lock()
+            .await
+            .execute(
+                "INSERT OR REPLACE INTO base (device, path, content) VALUES (?, ?, ?)",
+                rusqlite::params![uid, path, content],
+            )
+            .map(|_| ())
+    }
+    pub async fn del_base(&self, uid: &str, path: &str) -> Result<()> {
+        self.conn
+            .lock()
+            .await
+            .execute(
+                "DELETE FROM base WHERE device = ? AND path = ?",
                 [uid, path],
             )
             .map(|_| ())
-        } else {
-            conn.execute("DELETE FROM cache WHERE device = ?", [uid])
-                .map(|_| ())
+    }
+    pub async fn del(&self, uid: &str, path: &str) -> Result<()> {
+        info!("cache del: {} {}", uid, path);
+        let conn = self.conn.lock().await;
+        for table in ["cache", "digest", "base"] {
+            if !path.is_empty() {
+                conn.execute(
+                    &format!("DELETE FROM {} WHERE device = ? AND path = ?", table),
+                    [uid, path],
+                )?;
+            } else {
+                conn.execute(&format!("DELETE FROM {} WHERE device = ?", table), [uid])?;
+            }
         }
+        Ok(())
     }
 }
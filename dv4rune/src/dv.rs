@@ -3,7 +3,7 @@ use std::{collections::HashMap, future::IntoFuture, path::Path, sync::Arc};
 use dv_api::{
     fs::{CheckInfo, Metadata, OpenFlags},
     process::Interactor,
-    user::{Config, Dev, User},
+    user::{Config, Dev, Keyring, User},
     util::{Os, XPath},
     whatever,
 };
@@ -18,7 +18,7 @@ use tracing::info;
 use crate::{
     cache::SqliteCache,
     interactor::TermInteractor,
-    multi::{Context, Packages, action},
+    multi::{Captured, Context, CopyOpts, ExecOpts, Packages, action},
 };
 use support::Result as LRes;
 
@@ -46,6 +46,7 @@ pub struct Dv {
     users: HashMap<String, User>,
     cache: SqliteCache,
     interactor: TermInteractor,
+    keyring: Arc<Keyring>,
 }
 
 impl Dv {
@@ -56,6 +57,7 @@ impl Dv {
             users: HashMap::new(),
             cache: SqliteCache::new(path),
             interactor: TermInteractor::new().unwrap(),
+            keyring: Arc::default(),
         }
     }
     fn context(&self) -> Context<'_> {
@@ -74,6 +76,17 @@ impl Dv {
             .copy(src.1, dst.1)
             .await
     }
+    #[rune::function(path = Self::copy_with)]
+    async fn copy_with(
+        this: Ref<Self>,
+        src: (Ref<str>, Ref<str>),
+        dst: (Ref<str>, Ref<str>),
+        opts: Ref<CopyOpts>,
+    ) -> LRes<bool> {
+        crate::multi::CopyContext::with_opts(this.context(), &src.0, &dst.0, &opts)?
+            .copy(src.1, dst.1)
+            .await
+    }
     #[rune::function(path = Self::exec)]
     async fn exec(
         this: Ref<Self>,
@@ -81,7 +94,28 @@ impl Dv {
         shell: Option<Ref<str>>,
         commands: Ref<str>,
     ) -> LRes<bool> {
-        crate::multi::exec(&this.context(), uid, shell.as_deref(), commands).await
+        let opts = ExecOpts::default();
+        crate::multi::exec(&this.context(), uid, shell.as_deref(), commands, &opts).await
+    }
+    #[rune::function(path = Self::exec_with)]
+    async fn exec_with(
+        this: Ref<Self>,
+        uid: Ref<str>,
+        shell: Option<Ref<str>>,
+        commands: Ref<str>,
+        opts: Ref<ExecOpts>,
+    ) -> LRes<bool> {
+        crate::multi::exec(&this.context(), uid, shell.as_deref(), commands, &opts).await
+    }
+    #[rune::function(path = Self::capture)]
+    async fn capture(
+        this: Ref<Self>,
+        uid: Ref<str>,
+        shell: Option<Ref<str>>,
+        commands: Ref<str>,
+        read_only: bool,
+    ) -> LRes<Captured> {
+        crate::multi::capture(&this.context(), uid, shell.as_deref(), commands, read_only).await
     }
     #[rune::function(path = Self::auto)]
     async fn auto(
@@ -167,6 +201,7 @@ impl Dv {
 impl Dv {
     #[rune::function(path = Self::add_user)]
     async fn add_user(mut this: Mut<Dv>, id: Ref<str>, cfg: Config) -> LRes<()> {
+        let this = &mut *this;
         let id = id.as_ref();
         if this.users.contains_key(id) {
             whatever!("user {} already exists", id);
@@ -174,7 +209,9 @@ impl Dv {
         let u = if let Some(hid) = cfg.hid() {
             let hid = hid.to_string();
             if let Some(dev) = this.devices.get_mut(&hid) {
-                let u = cfg.connect(Some(dev.dev.clone())).await?;
+                let u = cfg
+                    .connect(Some(dev.dev.clone()), &this.interactor, &this.keyring)
+                    .await?;
                 if u.is_system {
                     dev.system = Some(id.to_string());
                 } else {
@@ -182,7 +219,7 @@ impl Dv {
                 }
                 u
             } else {
-                let u = cfg.connect(None).await?;
+                let u = cfg.connect(None, &this.interactor, &this.keyring).await?;
                 let mut dev = Device::new(u.dev.clone());
                 if u.is_system {
                     dev.system = Some(id.to_string());
@@ -193,7 +230,7 @@ impl Dv {
                 u
             }
         } else {
-            cfg.connect(None).await?
+            cfg.connect(None, &this.interactor, &this.keyring).await?
         };
         this.interactor
             .log(format!("user: {:<10}, os: {:<8}", id, u.dev.os.as_ref()))
@@ -225,8 +262,11 @@ pub fn module() -> Result<rune::Module, rune::ContextError> {
     crate::multi::register(&mut m)?;
     m.function_meta(Dv::add_user)?;
     m.function_meta(Dv::auto)?;
+    m.function_meta(Dv::capture)?;
     m.function_meta(Dv::copy)?;
+    m.function_meta(Dv::copy_with)?;
     m.function_meta(Dv::exec)?;
+    m.function_meta(Dv::exec_with)?;
     m.function_meta(Dv::load_src)?;
     m.function_meta(Dv::once)?;
     m.function_meta(Dv::os)?;
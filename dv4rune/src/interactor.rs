@@ -6,7 +6,8 @@ use crossterm::{
 };
 use dv_api::{
     Result,
-    process::{BoxedPty, BoxedPtyReader, BoxedPtyWriter, Interactor, WindowSize},
+    process::{BoxedPty, BoxedPtyReader, BoxedPtyWriter, Interactor, Signal, WindowSize},
+    whatever,
 };
 
 use tokio::{
@@ -44,17 +45,37 @@ impl Interactor for TermInteractor {
     async fn ask(&self, pty: BoxedPty) -> dv_api::Result<i32> {
         let (mut ctl, writer, reader) = pty.destruct();
         let oneshot = Oneshot::new();
+        let (interrupt, mut interrupts) = mpsc::unbounded_channel();
         self.q
             .send(Request::Ask(Ask {
                 writer,
                 reader,
                 exit: oneshot.clone(),
+                interrupt,
             }))
             .await
             .expect("send ask request");
-        let ec = ctl.wait().await?;
+        //NOTE:the first Ctrl-C only reaches the pty, a process still running after it is ended
+        let mut count = 0;
+        let res = loop {
+            tokio::select! {
+                res = ctl.wait() => break res,
+                Some(()) = interrupts.recv() => {
+                    count += 1;
+                    let sig = match count {
+                        1 => continue,
+                        2 => Signal::Term,
+                        _ => Signal::Kill,
+                    };
+                    debug!("send {} on Ctrl-C", sig);
+                    if let Err(e) = ctl.signal(sig).await {
+                        warn!("send {} failed: {}", sig, e);
+                    }
+                }
+            }
+        };
         oneshot.send(());
-        Ok(ec)
+        Ok(res?)
     }
     async fn confirm(&self, msg: String, opts: &[&str]) -> Result<usize> {
         let opts = opts
@@ -84,12 +105,28 @@ impl Interactor for TermInteractor {
         debug!("confirm selection {}", sel);
         Ok(sel)
     }
+    async fn prompt(&self, msg: String, echo: bool) -> Result<String> {
+        let oneshot = Oneshot::new();
+        self.q
+            .send(Request::Prompt(Prompt {
+                msg,
+                echo,
+                input: oneshot.clone(),
+            }))
+            .await
+            .expect("send prompt request");
+        match oneshot.wait() {
+            Some(input) => Ok(input),
+            None => whatever!("prompt cancelled"),
+        }
+    }
 }
 
 enum Request {
     Ask(Ask),
     Log(String),
     Confirm(Confirm),
+    Prompt(Prompt),
 }
 
 struct Ui {
@@ -113,6 +150,11 @@ impl Ui {
                         warn!("confirm failed: {}", e);
                     }
                 }
+                Some(Request::Prompt(p)) => {
+                    if let Err(e) = p.exec().await {
+                        warn!("prompt failed: {}", e);
+                    }
+                }
                 Option::None => {
                     break;
                 }
@@ -125,6 +167,8 @@ struct Ask {
     writer: BoxedPtyWriter,
     reader: BoxedPtyReader,
     exit: Oneshot<()>,
+    /// notified on every Ctrl-C
+    interrupt: mpsc::UnboundedSender<()>,
 }
 
 impl Ask {
@@ -133,6 +177,7 @@ impl Ask {
             mut writer,
             mut reader,
             exit,
+            interrupt,
         } = self;
         let _guard = RawModeGuard::new()?;
         debug!("start to sync stdin to pty");
@@ -166,7 +211,10 @@ impl Ask {
             }) = ev
             {
                 let bytes: &[u8] = match (modifiers, code) {
-                    (KeyModifiers::CONTROL, KeyCode::Char('c')) => "\x03".as_bytes(),
+                    (KeyModifiers::CONTROL, KeyCode::Char('c')) => {
+                        let _ = interrupt.send(());
+                        "\x03".as_bytes()
+                    }
                     (KeyModifiers::CONTROL, KeyCode::Char('d')) => "\x04".as_bytes(),
                     (_, KeyCode::Left) => "\x1b[D".as_bytes(),
                     (_, KeyCode::Right) => "\x1b[C".as_bytes(),
@@ -243,6 +291,68 @@ impl Confirm {
     }
 }
 
+struct Prompt {
+    msg: String,
+    echo: bool,
+    input: Oneshot<Option<String>>,
+}
+
+impl Prompt {
+    async fn exec(self) -> Result<()> {
+        let res = Self::read(&self.msg, self.echo);
+        //NOTE:always answer, the asker is blocked until then
+        self.input.send(res.as_ref().ok().cloned().flatten());
+        res.map(|_| ())
+    }
+    fn read(msg: &str, echo: bool) -> Result<Option<String>> {
+        let mut stdout = std::io::stdout();
+        print!("{} ", msg);
+        stdout.flush()?;
+        let _guard = RawModeGuard::new()?;
+        let mut input = String::new();
+        loop {
+            if !event::poll(Duration::from_millis(100))? {
+                continue;
+            }
+            let Event::Key(KeyEvent {
+                code,
+                modifiers,
+                kind: KeyEventKind::Press,
+                ..
+            }) = event::read()?
+            else {
+                continue;
+            };
+            match (modifiers, code) {
+                (KeyModifiers::CONTROL, KeyCode::Char('c')) => {
+                    drop(_guard);
+                    println!();
+                    return Ok(None);
+                }
+                (_, KeyCode::Enter) => {
+                    drop(_guard);
+                    println!();
+                    return Ok(Some(input));
+                }
+                (_, KeyCode::Backspace) => {
+                    if input.pop().is_some() && echo {
+                        stdout.write_all(b"\x08 \x08")?;
+                        stdout.flush()?;
+                    }
+                }
+                (m, KeyCode::Char(c)) if !m.contains(KeyModifiers::CONTROL) => {
+                    input.push(c);
+                    if echo {
+                        write!(stdout, "{}", c)?;
+                        stdout.flush()?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
 struct RawModeGuard;
 impl RawModeGuard {
     fn new() -> std::io::Result<Self> {
@@ -256,3 +366,31 @@ impl Drop for RawModeGuard {
         disable_raw_mode().expect("disable raw mode");
     }
 }
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Request, TermInteractor};
+    use tokio::sync::mpsc;
+
+    /// The logs of a `TermInteractor` made by `with_logs`, any other request is dropped.
+    pub struct Logs(mpsc::Receiver<Request>);
+
+    impl Logs {
+        pub fn take(&mut self) -> Vec<String> {
+            let mut logs = Vec::new();
+            while let Ok(req) = self.0.try_recv() {
+                if let Request::Log(msg) = req {
+                    logs.push(msg);
+                }
+            }
+            logs
+        }
+    }
+
+    impl TermInteractor {
+        pub fn with_logs() -> (Self, Logs) {
+            let (tx, rx) = mpsc::channel(100);
+            (Self { q: tx }, Logs(rx))
+        }
+    }
+}
@@ -45,14 +45,16 @@ impl<'s> Context<'s> {
 }
 
 mod copy;
+mod diff;
+mod merge;
 mod user;
-pub use copy::CopyContext;
+pub use copy::{CopyContext, CopyOpts};
 mod pm;
 pub use pm::{Packages, pm};
 mod auto;
 pub use auto::auto;
 mod exec;
-pub use exec::exec;
+pub use exec::{Captured, ExecOpts, capture, exec};
 mod os;
 mod util;
 
@@ -68,5 +70,7 @@ pub fn register(m: &mut rune::module::Module) -> Result<(), rune::ContextError>
     user::register(m)?;
     os::register(m)?;
     pm::register(m)?;
+    exec::register(m)?;
+    copy::register(m)?;
     Ok(())
 }
@@ -1,9 +1,59 @@
-use std::{borrow::Cow, ops::Deref};
+use std::{borrow::Cow, collections::HashSet, ops::Deref};
 
-use super::dev::*;
+use super::{
+    dev::*,
+    diff::render,
+    merge::{has_markers, merge3},
+};
 use dv_api::{fs::*, user::User, util::*, whatever};
+use tokio::io::AsyncWriteExt;
 use tracing::{debug, trace};
 
+/// Files up to this size keep their content as the base of a later merge.
+const BASE_LIMIT: u64 = 256 * 1024;
+/// Appended to a source file for its merge with conflicts left to resolve.
+const MERGE_SUFFIX: &str = ".merge";
+
+/// Options of `Dv::copy_with`.
+#[derive(Debug, Default, rune::Any)]
+pub struct CopyOpts {
+    /// what to do without asking, `y` to overwrite, `u` to update or `n` to skip
+    #[rune(get, set)]
+    confirm: Option<String>,
+    /// compare the contents instead of the mtimes to find what changed
+    #[rune(get, set)]
+    hash: bool,
+    /// delete the files of a copied directory that are gone from the source
+    #[rune(get, set)]
+    mirror: bool,
+    /// keep small text files in the cache as they were copied, to offer merging them when
+    /// both sides changed
+    #[rune(get, set)]
+    merge: bool,
+    /// gitignore-style patterns of the files to leave out of a directory copy
+    ignore: Vec<String>,
+}
+
+impl CopyOpts {
+    #[rune::function(path = Self::new)]
+    pub fn new() -> CopyOpts {
+        CopyOpts::default()
+    }
+
+    /// Add a pattern after those of the `.dvignore` in the source directory.
+    #[rune::function(instance)]
+    pub fn ignore(&mut self, pattern: &str) {
+        self.ignore.push(pattern.to_string());
+    }
+}
+
+pub fn register(m: &mut rune::module::Module) -> Result<(), rune::ContextError> {
+    m.ty::<CopyOpts>()?;
+    m.function_meta(CopyOpts::new)?;
+    m.function_meta(CopyOpts::ignore)?;
+    Ok(())
+}
+
 pub struct CopyContext<'a> {
     ctx: Context<'a>,
     src: &'a User,
@@ -11,6 +61,10 @@ pub struct CopyContext<'a> {
     dst: &'a User,
     dst_uid: &'a str,
     opt: Option<&'a str>,
+    hash: bool,
+    mirror: bool,
+    merge: bool,
+    ignore: &'a [String],
 }
 
 impl<'a> Deref for CopyContext<'a> {
@@ -40,8 +94,190 @@ impl<'a> CopyContext<'a> {
             dst,
             dst_uid,
             opt,
+            hash: false,
+            mirror: false,
+            merge: false,
+            ignore: &[],
         })
     }
+    pub fn with_opts(
+        ctx: Context<'a>,
+        src_uid: &'a str,
+        dst_uid: &'a str,
+        opts: &'a CopyOpts,
+    ) -> LRes<Self> {
+        let mut this = Self::new(ctx, src_uid, dst_uid, opts.confirm.as_deref())?;
+        this.hash = opts.hash;
+        this.mirror = opts.mirror;
+        this.merge = opts.merge;
+        this.ignore = &opts.ignore;
+        Ok(this)
+    }
+
+    /// Show what overwriting would change, from the destination to the source.
+    async fn show_diff(&self, src_path: &XPath, dst_path: &XPath) -> LRes<()> {
+        let src = read_all(self.src, src_path).log(self.interactor).await?;
+        let dst = read_all(self.dst, dst_path).log(self.interactor).await?;
+        let out = render(
+            &format!("{}:{}", self.dst_uid, dst_path),
+            &dst,
+            &format!("{}:{}", self.src_uid, src_path),
+            &src,
+        );
+        self.interactor.log(out).await;
+        Ok(())
+    }
+
+    /// Merge the changes of both sides against the content at the last copy and write the
+    /// result to both. Conflicts are left to `$EDITOR`, or to the user in a `.merge` file next
+    /// to the source, which holds back copying the file until it is resolved and removed.
+    /// In dry-run mode conflicts are only reported.
+    async fn merge(&self, src_path: &XPath, dst_path: &XPath, base: &[u8]) -> LRes<bool> {
+        let src = read_all(self.src, src_path).log(self.interactor).await?;
+        let dst = read_all(self.dst, dst_path).log(self.interactor).await?;
+        let (Ok(base), Ok(src), Ok(dst)) = (
+            std::str::from_utf8(base),
+            String::from_utf8(src),
+            String::from_utf8(dst),
+        ) else {
+            self.interactor
+                .log(format!("{} is not text, can not merge", dst_path))
+                .await;
+            return Ok(false);
+        };
+        let src_name = format!("{}:{}", self.src_uid, src_path);
+        let dst_name = format!("{}:{}", self.dst_uid, dst_path);
+        let merged = merge3(base, &src, &dst, &src_name, &dst_name);
+        let text = if merged.conflicts == 0 {
+            merged.text
+        } else if self.dry_run {
+            self.interactor
+                .log(format!(
+                    "{} conflicts of {} are left to resolve",
+                    merged.conflicts, dst_name
+                ))
+                .await;
+            return Ok(false);
+        } else if let Ok(editor) = std::env::var("EDITOR") {
+            let text = edit(editor, merged.text).await?;
+            if has_markers(&text) {
+                self.interactor
+                    .log(format!("conflicts of {} are not resolved", dst_name))
+                    .await;
+                return Ok(false);
+            }
+            text
+        } else {
+            let pending = XPathBuf::from(format!("{}{}", src_path, MERGE_SUFFIX).as_str());
+            write_all(self.src, &pending, merged.text.as_bytes())
+                .log(self.interactor)
+                .await?;
+            self.interactor
+                .log(format!(
+                    "{} conflicts are marked in {}:{}, resolve them into {} and remove it",
+                    merged.conflicts, self.src_uid, pending, src_name
+                ))
+                .await;
+            return Ok(false);
+        };
+        if self.dry_run {
+            return Ok(true);
+        }
+        let content = text.as_bytes();
+        write_all(self.src, src_path, content)
+            .log(self.interactor)
+            .await?;
+        write_all(self.dst, dst_path, content)
+            .log(self.interactor)
+            .await?;
+        let (Some(src_ts), Some(dst_ts)) = (
+            self.src.get_mtime(src_path).await?,
+            self.dst.get_mtime(dst_path).await?,
+        ) else {
+            whatever!("get {} mtime failed", dst_name)
+        };
+        self.cache
+            .set(self.dst_uid, dst_path.as_str(), src_ts, dst_ts)
+            .log(self.interactor)
+            .await?;
+        self.cache
+            .set_digest(self.dst_uid, dst_path.as_str(), &digest_bytes(content))
+            .log(self.interactor)
+            .await?;
+        self.cache
+            .set_base(self.dst_uid, dst_path.as_str(), content)
+            .log(self.interactor)
+            .await?;
+        Ok(true)
+    }
+
+    /// Copy from the source if `do_`, or back from the destination. With merging enabled a
+    /// small file goes through memory instead, and its content is returned to become the base.
+    async fn transfer(
+        &self,
+        do_: bool,
+        src_path: &XPath,
+        dst_path: &XPath,
+        attr: &FileAttributes,
+    ) -> LRes<Option<Vec<u8>>> {
+        let ((from, from_uid, from_path), (to, to_uid, to_path)) = if do_ {
+            (
+                (self.src, self.src_uid, src_path),
+                (self.dst, self.dst_uid, dst_path),
+            )
+        } else {
+            (
+                (self.dst, self.dst_uid, dst_path),
+                (self.src, self.src_uid, src_path),
+            )
+        };
+        if !self.merge || attr.size.is_none_or(|size| size > BASE_LIMIT) {
+            try_copy(from, from_uid, from_path, to, to_uid, to_path).await?;
+            return Ok(None);
+        }
+        let content = read_all(from, from_path).log(self.interactor).await?;
+        let attr = FileAttributes {
+            permissions: attr.permissions.map(|mode| mode & 0o777),
+            ..Default::default()
+        };
+        let mut file = to
+            .open_with_attr(
+                to_path,
+                OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+                attr,
+            )
+            .log(self.interactor)
+            .await?;
+        file.write_all(&content).await?;
+        file.shutdown().await?;
+        Ok(Some(content))
+    }
+
+    /// Keep the copied content as the base of a later merge, or forget a stale one.
+    async fn record_base(&self, dst_path: &XPath, content: Option<Vec<u8>>) -> LRes<()> {
+        if let Some(content) = content
+            && std::str::from_utf8(&content).is_ok()
+        {
+            return self
+                .cache
+                .set_base(self.dst_uid, dst_path.as_str(), &content)
+                .log(self.interactor)
+                .await;
+        }
+        self.cache
+            .del_base(self.dst_uid, dst_path.as_str())
+            .log(self.interactor)
+            .await
+    }
+
+    /// Whether a merge of `src_path` with conflicts waits to be resolved.
+    async fn pending_merge(&self, src_path: &XPath) -> LRes<bool> {
+        if !self.merge {
+            return Ok(false);
+        }
+        let pending = XPathBuf::from(format!("{}{}", src_path, MERGE_SUFFIX).as_str());
+        Ok(self.src.get_mtime(&pending).await?.is_some())
+    }
 
     async fn check_copy_file(
         &self,
@@ -54,22 +290,72 @@ impl<'a> CopyContext<'a> {
             "check_copy_file {}:{} -> {}:{}",
             self.src_uid, src_path, self.dst_uid, dst_path
         );
-        let cache = self
-            .ctx
-            .cache
-            .get(self.dst_uid, dst_path.as_str())
-            .log(self.interactor)
-            .await?;
-        let overwrite = src_attr.mtime.is_some_and(|mt| {
-            cache.is_none_or(|(ver, _)| ver != mt as i64) || dst_attr.mtime.is_none()
-        });
-        let update = dst_attr.mtime.is_some_and(|mt| {
-            cache.is_none_or(|(_, old)| old != mt as i64) || src_attr.mtime.is_none()
-        });
-        debug!(
-            "{}:{}({:?}) - {}:{}({:?}) - {:?}",
-            self.src_uid, src_path, src_attr.mtime, self.dst_uid, dst_path, dst_attr.mtime, cache
-        );
+        if self.pending_merge(src_path).await? {
+            action!(
+                self,
+                false,
+                "copy {}:{}, conflicts in {} are not resolved",
+                self.src_uid,
+                src_path,
+                MERGE_SUFFIX
+            );
+            return Ok(false);
+        }
+        let (overwrite, update, digests) = if self.hash {
+            let cached = self
+                .cache
+                .get_digest(self.dst_uid, dst_path.as_str())
+                .log(self.interactor)
+                .await?;
+            let src_hash = digest(self.src, src_path).await?;
+            let dst_hash = match dst_attr.mtime {
+                Some(_) => Some(digest(self.dst, dst_path).await?),
+                None => None,
+            };
+            debug!(
+                "{}:{}({}) - {}:{}({:?}) - {:?}",
+                self.src_uid, src_path, src_hash, self.dst_uid, dst_path, dst_hash, cached
+            );
+            let same = dst_hash.as_ref() == Some(&src_hash);
+            if same && cached.as_ref() != Some(&src_hash) && !self.dry_run {
+                //NOTE:already in sync, remember it so that later changes are attributed
+                self.cache
+                    .set_digest(self.dst_uid, dst_path.as_str(), &src_hash)
+                    .log(self.interactor)
+                    .await?;
+            }
+            let overwrite = !same && (dst_hash.is_none() || cached.as_ref() != Some(&src_hash));
+            let update = !same
+                && dst_hash
+                    .as_ref()
+                    .is_some_and(|h| cached.as_ref() != Some(h));
+            (overwrite, update, Some((src_hash, dst_hash)))
+        } else {
+            let cache = self
+                .ctx
+                .cache
+                .get(self.dst_uid, dst_path.as_str())
+                .log(self.interactor)
+                .await?;
+            let overwrite = src_attr.mtime.is_some_and(|mt| {
+                cache.is_none_or(|(ver, _)| ver != mt as i64) || dst_attr.mtime.is_none()
+            });
+            let update = dst_attr.mtime.is_some_and(|mt| {
+                cache.is_none_or(|(_, old)| old != mt as i64) || src_attr.mtime.is_none()
+            });
+            debug!(
+                "{}:{}({:?}) - {}:{}({:?}) - {:?}",
+                self.src_uid,
+                src_path,
+                src_attr.mtime,
+                self.dst_uid,
+                dst_path,
+                dst_attr.mtime,
+                cache
+            );
+            (overwrite, update, None)
+        };
+        let mut merged = false;
         let res = match (self.opt, overwrite, update) {
             (Some("y"), true, _) => Some(true),
             (Some("u"), _, true) => Some(false),
@@ -78,64 +364,85 @@ impl<'a> CopyContext<'a> {
             _ => {
                 let mut hint = String::new();
                 let mut opts = Vec::new();
+                let changed = if self.hash {
+                    " changed, "
+                } else {
+                    " is newer, "
+                };
                 if overwrite {
                     hint.push_str(self.src_uid);
                     hint.push(':');
                     hint.push_str(src_path.as_str());
-                    hint.push_str(" is newer, ");
+                    hint.push_str(changed);
                     opts.push("y/overwrite");
                 }
                 if update {
                     hint.push_str(self.dst_uid);
                     hint.push(':');
                     hint.push_str(dst_path.as_str());
-                    hint.push_str(" is newer, ");
+                    hint.push_str(changed);
                     opts.push("u/update");
                 }
                 hint.push_str("do what?");
                 opts.push("n/skip");
-                let sel = self
-                    .interactor
-                    .confirm(hint, &opts)
-                    .log(self.interactor)
-                    .await?;
-                match opts[sel].chars().nth(0) {
-                    Some('y') => Some(true),
-                    Some('u') => Some(false),
-                    Some('n') => None,
-                    _ => unreachable!(),
+                if dst_attr.mtime.is_some() {
+                    opts.push("d/diff");
+                }
+                let base = if self.merge && overwrite && update {
+                    self.cache
+                        .get_base(self.dst_uid, dst_path.as_str())
+                        .log(self.interactor)
+                        .await?
+                } else {
+                    None
+                };
+                if base.is_some() {
+                    opts.push("m/merge");
+                }
+                loop {
+                    let sel = self
+                        .interactor
+                        .confirm(hint.clone(), &opts)
+                        .log(self.interactor)
+                        .await?;
+                    match opts[sel].chars().nth(0) {
+                        Some('y') => break Some(true),
+                        Some('u') => break Some(false),
+                        Some('n') => break None,
+                        Some('d') => self.show_diff(src_path, dst_path).await?,
+                        Some('m') => {
+                            if self
+                                .merge(src_path, dst_path, base.as_deref().unwrap_or_default())
+                                .await?
+                            {
+                                merged = true;
+                                break None;
+                            }
+                        }
+                        _ => unreachable!(),
+                    }
                 }
             }
         };
         if let Some(do_) = if !self.dry_run { res } else { None } {
-            let (src_ts, dst_ts) = if do_ {
-                try_copy(
-                    self.src,
-                    self.src_uid,
+            let copied = self
+                .transfer(
+                    do_,
                     src_path,
-                    self.dst,
-                    self.dst_uid,
                     dst_path,
+                    if do_ { &src_attr } else { &dst_attr },
                 )
                 .await?;
+            let (src_ts, dst_ts) = if do_ {
                 let src_ts = match src_attr.mtime {
                     Some(ts) => Some(ts as i64),
                     None => self.src.get_mtime(src_path).await?,
                 };
                 (src_ts, self.dst.get_mtime(dst_path).await?)
             } else {
-                try_copy(
-                    self.dst,
-                    self.dst_uid,
-                    dst_path,
-                    self.src,
-                    self.src_uid,
-                    src_path,
-                )
-                .await?;
-                let dst_ts = match src_attr.mtime {
+                let dst_ts = match dst_attr.mtime {
                     Some(ts) => Some(ts as i64),
-                    None => self.src.get_mtime(src_path).await?,
+                    None => self.dst.get_mtime(dst_path).await?,
                 };
                 (self.src.get_mtime(src_path).await?, dst_ts)
             };
@@ -149,20 +456,37 @@ impl<'a> CopyContext<'a> {
                 .set(self.dst_uid, dst_path.as_str(), src_ts, dst_ts)
                 .log(self.interactor)
                 .await?;
+            if let Some((src_hash, dst_hash)) = &digests {
+                let hash = match dst_hash {
+                    Some(dst_hash) if !do_ => dst_hash,
+                    _ => src_hash,
+                };
+                self.cache
+                    .set_digest(self.dst_uid, dst_path.as_str(), hash)
+                    .log(self.interactor)
+                    .await?;
+            }
+            self.record_base(dst_path, copied).await?;
         }
         let update = res.is_some_and(|do_| !do_);
         action!(
             self,
-            res.is_some(),
+            res.is_some() || merged,
             "{} {}:{} {} {}:{}",
-            if update { "update" } else { "copy" },
+            if merged {
+                "merge"
+            } else if update {
+                "update"
+            } else {
+                "copy"
+            },
             self.src_uid,
             src_path,
             if update { "<-" } else { "->" },
             self.dst_uid,
             dst_path
         );
-        Ok(res.is_some())
+        Ok(res.is_some() || merged)
     }
 
     async fn check_copy_dir(
@@ -170,11 +494,27 @@ impl<'a> CopyContext<'a> {
         src_path: XPathBuf,
         dst_path: XPathBuf,
         meta: Vec<Metadata>,
+        ignore: Ignore,
     ) -> LRes<bool> {
         let mut success = false;
         let mut src_file = src_path.clone();
         let mut dst_file = dst_path.clone();
+        let copied = if self.mirror {
+            meta.iter().map(|m| m.path.to_string()).collect()
+        } else {
+            HashSet::new()
+        };
+        //NOTE:a merge left with conflicts is never deployed, even when not merging this time
+        let listed: HashSet<_> = meta.iter().map(|m| m.path.to_string()).collect();
         for Metadata { path, attr } in meta {
+            if path
+                .as_str()
+                .strip_suffix(MERGE_SUFFIX)
+                .is_some_and(|stem| listed.contains(stem))
+            {
+                debug!("skip pending merge {}", path);
+                continue;
+            }
             src_file.push(&path);
             dst_file.push(&path);
             let (full_dst_file, dst_attr) = self.dst.check_file(&dst_file).await;
@@ -190,9 +530,111 @@ impl<'a> CopyContext<'a> {
             dst_file.clone_from(&dst_path);
             success |= res;
         }
+        if self.mirror {
+            success |= self.prune(dst_path, &copied, ignore).await?;
+        }
         Ok(success)
     }
 
+    /// Delete the files under `dst_path` which were copied before but are not in `copied`,
+    /// files never copied by us or ignored in the source are left alone, and those modified
+    /// since they were copied are only deleted when confirmed.
+    async fn prune(
+        &self,
+        dst_path: XPathBuf,
+        copied: &HashSet<String>,
+        ignore: Ignore,
+    ) -> LRes<bool> {
+        let files = match self.dst.list_dir(dst_path.clone(), ignore).await {
+            Ok(dir) => dir.files,
+            Err(e) if e.is_not_found() => return Ok(false),
+            Err(e) => Err(e)?,
+        };
+        let mut success = false;
+        let mut dst_file = dst_path.clone();
+        for Metadata { path, attr } in files {
+            if copied.contains(path.as_str()) {
+                continue;
+            }
+            dst_file.push(&path);
+            let Some((_, latest)) = self
+                .cache
+                .get(self.dst_uid, dst_file.as_str())
+                .log(self.interactor)
+                .await?
+            else {
+                dst_file.clone_from(&dst_path);
+                continue;
+            };
+            let modified = self.modified(&dst_file, &attr, latest).await?;
+            let res = self.confirm_delete(&dst_file, modified).await?;
+            if res && !self.dry_run {
+                self.dst.remove(&dst_file).log(self.interactor).await?;
+                self.cache
+                    .del(self.dst_uid, dst_file.as_str())
+                    .log(self.interactor)
+                    .await?;
+            }
+            action!(
+                self,
+                res,
+                "delete {}:{}{}",
+                self.dst_uid,
+                dst_file,
+                if modified {
+                    ", modified since copied"
+                } else {
+                    ""
+                }
+            );
+            dst_file.clone_from(&dst_path);
+            success |= res;
+        }
+        Ok(success)
+    }
+
+    /// Whether the destination file changed after it was copied, by its digest in hash mode
+    /// and by its mtime otherwise.
+    async fn modified(&self, dst_path: &XPath, attr: &FileAttributes, latest: i64) -> LRes<bool> {
+        if self.hash
+            && let Some(cached) = self
+                .cache
+                .get_digest(self.dst_uid, dst_path.as_str())
+                .log(self.interactor)
+                .await?
+        {
+            return Ok(digest(self.dst, dst_path).await? != cached);
+        }
+        Ok(attr.mtime.is_none_or(|mt| mt as i64 != latest))
+    }
+
+    /// A file modified on the destination is never deleted without asking.
+    async fn confirm_delete(&self, dst_path: &XPath, modified: bool) -> LRes<bool> {
+        match self.opt {
+            Some("y") if !modified => Ok(true),
+            Some("y") | Some("n") | Some("u") => Ok(false),
+            _ => {
+                let hint = format!(
+                    "{}:{} is gone from {}{}, delete it?",
+                    self.dst_uid,
+                    dst_path,
+                    self.src_uid,
+                    if modified {
+                        " but was modified since copied"
+                    } else {
+                        ""
+                    }
+                );
+                let sel = self
+                    .interactor
+                    .confirm(hint, &["y/delete", "n/keep"])
+                    .log(self.interactor)
+                    .await?;
+                Ok(sel == 0)
+            }
+        }
+    }
+
     pub async fn copy(&self, src_path: impl AsRef<str>, dst_path: impl AsRef<str>) -> LRes<bool> {
         let src_path = src_path.as_ref();
         let dst_path: &str = dst_path.as_ref();
@@ -215,12 +657,24 @@ impl<'a> CopyContext<'a> {
             }
         };
         if src_path.ends_with('/') {
-            let DirInfo { path, files } = self.src.check_dir(src_path).log(self.interactor).await?;
+            let DirInfo {
+                path,
+                files,
+                ignore,
+            } = self
+                .src
+                .check_dir_with(src_path, self.ignore)
+                .log(self.interactor)
+                .await?;
             let (dst_path, fa) = self.dst.check_file(dst_path.into()).await;
             confirm(fa, true)?;
-            self.check_copy_dir(path, dst_path, files).await
+            self.check_copy_dir(path, dst_path, files, ignore).await
         } else {
-            let info = self.src.check_path(src_path).log(self.interactor).await?;
+            let info = self
+                .src
+                .check_path_with(src_path, self.ignore)
+                .log(self.interactor)
+                .await?;
             let dst_path2 = if dst_path.ends_with('/') {
                 format!(
                     "{}{}",
@@ -238,7 +692,8 @@ impl<'a> CopyContext<'a> {
             match info {
                 CheckInfo::Dir(dir) => {
                     confirm(fa, true)?;
-                    self.check_copy_dir(dir.path, dst_path2, dir.files).await
+                    self.check_copy_dir(dir.path, dst_path2, dir.files, dir.ignore)
+                        .await
                 }
                 CheckInfo::File(file) => {
                     let dst_ts = confirm(fa, false)?;
@@ -250,16 +705,45 @@ impl<'a> CopyContext<'a> {
     }
 }
 
+/// Let the user resolve the conflicts of `text` in `editor`, which may carry arguments.
+async fn edit(editor: String, text: String) -> LRes<String> {
+    let text = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+        let file = tempfile::NamedTempFile::new()?;
+        std::fs::write(file.path(), text)?;
+        let mut words = editor.split_whitespace();
+        let Some(program) = words.next() else {
+            return Err(std::io::Error::other("empty EDITOR"));
+        };
+        let status = std::process::Command::new(program)
+            .args(words)
+            .arg(file.path())
+            .status()?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!(
+                "{} exited with {}",
+                editor, status
+            )));
+        }
+        std::fs::read_to_string(file.path())
+    })
+    .await??;
+    Ok(text)
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, path::Path, time::Duration};
+    use std::{collections::HashMap, path::Path, sync::Arc, time::Duration};
 
-    use crate::{cache::SqliteCache, dv::tests::TestDv, interactor::TermInteractor};
+    use crate::{
+        cache::SqliteCache,
+        dv::tests::TestDv,
+        interactor::{TermInteractor, tests::Logs},
+    };
 
     use assert_fs::{TempDir, fixture::ChildPath, prelude::*};
-    use dv_api::user::Config;
+    use dv_api::user::{Config, Keyring};
 
-    use super::CopyContext;
+    use super::{super::util::digest_bytes, CopyContext, CopyOpts};
 
     ///Prepare a test environment with a source and destination directory.
     /// # Parameters
@@ -273,7 +757,11 @@ mod tests {
         let mut cfg = Config::default();
         cfg.insert("MOUNT", dir.to_string_lossy());
         let mut users = HashMap::new();
-        users.insert("this".to_string(), cfg.connect(None).await.unwrap());
+        let u = cfg
+            .connect(None, &int, &Arc::<Keyring>::default())
+            .await
+            .unwrap();
+        users.insert("this".to_string(), u);
         let src_dir = dir.child("src");
         for (name, content) in src {
             let f = src_dir.child(name);
@@ -389,6 +877,251 @@ mod tests {
         cache_assert(ctx.cache, src.child("f1").path(), dst.child("f1").path()).await;
     }
     #[tokio::test]
+    async fn hash_ignores_touch() {
+        let (dv, dir) = tenv(&[("f0", "f0")], &[]).await;
+        let opts = CopyOpts {
+            confirm: Some("y".to_string()),
+            hash: true,
+            ..Default::default()
+        };
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(
+            ctx.copy("src/", "dst").await.unwrap(),
+            "sync should success"
+        );
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let src = dir.child("src");
+        src.child("f0").write_str("f0").unwrap();
+        assert!(
+            !ctx.copy("src/", "dst").await.unwrap(),
+            "same content should not be copied"
+        );
+        src.child("f0").write_str("f1").unwrap();
+        assert!(
+            ctx.copy("src/", "dst").await.unwrap(),
+            "sync should success"
+        );
+        dir.child("dst/f0").assert("f1");
+    }
+    #[tokio::test]
+    async fn merge_writes_both() {
+        let (dv, dir) = tenv(&[("f0", "a\nb\nc\n")], &[]).await;
+        let opts = CopyOpts {
+            confirm: Some("y".to_string()),
+            merge: true,
+            ..Default::default()
+        };
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(ctx.copy("src/", "dst").await.unwrap());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let (src, dst) = (dir.child("src/f0"), dir.child("dst/f0"));
+        src.write_str("A\nb\nc\n").unwrap();
+        dst.write_str("a\nb\nC\n").unwrap();
+        let key = dst.to_str().unwrap();
+        let base = ctx.cache.get_base("this", key).await.unwrap().unwrap();
+        assert!(
+            ctx.merge(src.to_str().unwrap().into(), key.into(), &base)
+                .await
+                .unwrap()
+        );
+        let merged = "A\nb\nC\n";
+        src.assert(merged);
+        dst.assert(merged);
+        assert_eq!(
+            ctx.cache.get_base("this", key).await.unwrap().as_deref(),
+            Some(merged.as_bytes())
+        );
+        assert_eq!(
+            ctx.cache.get_digest("this", key).await.unwrap(),
+            Some(digest_bytes(merged.as_bytes()))
+        );
+        cache_assert(ctx.cache, src.path(), dst.path()).await;
+        assert!(
+            !ctx.copy("src/", "dst").await.unwrap(),
+            "merged files should be in sync"
+        );
+    }
+    #[tokio::test]
+    async fn pending_merge_holds_copy() {
+        let (dv, dir) = tenv(
+            &[("f0", "f0"), ("f0.merge", "<<<<<<< src"), ("f1", "f1")],
+            &[],
+        )
+        .await;
+        let opts = CopyOpts {
+            confirm: Some("y".to_string()),
+            merge: true,
+            ..Default::default()
+        };
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(ctx.copy("src/", "dst").await.unwrap());
+        dir.child("dst/f1").assert("f1");
+        for name in ["f0", "f0.merge"] {
+            assert!(!dir.child("dst").child(name).path().exists(), "{}", name);
+        }
+    }
+    #[tokio::test]
+    async fn pending_merge_never_deployed() {
+        let (dv, dir) = tenv(&[("f0", "f0"), ("f0.merge", "<<<<<<< src")], &[]).await;
+        let opts = CopyOpts {
+            confirm: Some("y".to_string()),
+            ..Default::default()
+        };
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(ctx.copy("src/", "dst").await.unwrap());
+        dir.child("dst/f0").assert("f0");
+        assert!(!dir.child("dst/f0.merge").path().exists());
+    }
+    #[tokio::test]
+    async fn dry_run_leaves_conflicts() {
+        let (mut dv, dir) = tenv(&[("f0", "a\n")], &[("f0", "b\n")]).await;
+        dv.dry_run = true;
+        let ctx =
+            CopyContext::with_opts(dv.context(), "this", "this", &CopyOpts::default()).unwrap();
+        let (src, dst) = (dir.child("src/f0"), dir.child("dst/f0"));
+        assert!(
+            !ctx.merge(
+                src.to_str().unwrap().into(),
+                dst.to_str().unwrap().into(),
+                b"c\n"
+            )
+            .await
+            .unwrap()
+        );
+        src.assert("a\n");
+        dst.assert("b\n");
+        assert!(!dir.child("src/f0.merge").path().exists());
+    }
+    #[tokio::test]
+    async fn base_only_with_merge() {
+        let (dv, dir) = tenv(&[("f0", "f0")], &[]).await;
+        let dst = dir.child("dst/f0");
+        let key = dst.to_str().unwrap();
+        let mut opts = CopyOpts {
+            confirm: Some("y".to_string()),
+            ..Default::default()
+        };
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(ctx.copy("src/", "dst").await.unwrap());
+        assert_eq!(ctx.cache.get_base("this", key).await.unwrap(), None);
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        dir.child("src/f0").write_str("f1").unwrap();
+        opts.merge = true;
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(ctx.copy("src/", "dst").await.unwrap());
+        dst.assert("f1");
+        assert_eq!(
+            ctx.cache.get_base("this", key).await.unwrap().as_deref(),
+            Some(&b"f1"[..])
+        );
+    }
+    #[tokio::test]
+    async fn mirror_prunes_copied() {
+        let (dv, dir) = tenv(&[("f0", "f0"), ("f1", "f1")], &[("local", "local")]).await;
+        let opts = CopyOpts {
+            confirm: Some("y".to_string()),
+            mirror: true,
+            ..Default::default()
+        };
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(
+            ctx.copy("src/", "dst").await.unwrap(),
+            "sync should success"
+        );
+        std::fs::remove_file(dir.child("src/f1").path()).unwrap();
+        assert!(
+            ctx.copy("src/", "dst").await.unwrap(),
+            "prune should success"
+        );
+        dir.child("dst/f0").assert("f0");
+        assert!(!dir.child("dst/f1").path().exists());
+        dir.child("dst/local").assert("local");
+    }
+    /// Copy `src/` to `dst` mirrored, then remove `src/f1` and log into the returned `Logs`.
+    async fn mirror_then_remove() -> (TestDv, TempDir, Logs) {
+        let (mut dv, dir) = tenv(&[("f0", "f0"), ("f1", "f1")], &[]).await;
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &mirror()).unwrap();
+        assert!(ctx.copy("src/", "dst").await.unwrap());
+        std::fs::remove_file(dir.child("src/f1").path()).unwrap();
+        let (int, logs) = TermInteractor::with_logs();
+        dv.interactor = int;
+        (dv, dir, logs)
+    }
+    fn mirror() -> CopyOpts {
+        CopyOpts {
+            confirm: Some("y".to_string()),
+            mirror: true,
+            ..Default::default()
+        }
+    }
+    #[tokio::test]
+    async fn prune_keeps_modified() {
+        let (dv, dir, mut logs) = mirror_then_remove().await;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        dir.child("dst/f1").write_str("changed").unwrap();
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &mirror()).unwrap();
+        assert!(!ctx.copy("src/", "dst").await.unwrap());
+        dir.child("dst/f1").assert("changed");
+        let logs = logs.take();
+        assert!(
+            logs.iter().any(|l| l.starts_with("[a] skip delete this:")
+                && l.ends_with("f1, modified since copied")),
+            "{:?}",
+            logs
+        );
+    }
+    #[tokio::test]
+    async fn prune_dry_run() {
+        let (mut dv, dir, mut logs) = mirror_then_remove().await;
+        dv.dry_run = true;
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &mirror()).unwrap();
+        assert!(ctx.copy("src/", "dst").await.unwrap());
+        dir.child("dst/f1").assert("f1");
+        let key = dir.child("dst/f1");
+        assert!(
+            ctx.cache
+                .get("this", key.to_str().unwrap())
+                .await
+                .unwrap()
+                .is_some()
+        );
+        let logs = logs.take();
+        assert!(
+            logs.iter()
+                .any(|l| l.starts_with("[n] exec delete this:") && l.ends_with("f1")),
+            "{:?}",
+            logs
+        );
+    }
+    #[tokio::test]
+    async fn ignore_patterns() {
+        let (dv, dir) = tenv(
+            &[
+                (".dvignore", "*.swp\nbuild/\n"),
+                ("f0", "f0"),
+                ("f0.swp", "swap"),
+                ("keep.swp", "keep"),
+                ("build/out", "out"),
+            ],
+            &[],
+        )
+        .await;
+        let mut opts = CopyOpts {
+            confirm: Some("y".to_string()),
+            ..Default::default()
+        };
+        opts.ignore.push("!keep.swp".to_string());
+        let ctx = CopyContext::with_opts(dv.context(), "this", "this", &opts).unwrap();
+        assert!(
+            ctx.copy("src/", "dst").await.unwrap(),
+            "sync should success"
+        );
+        content_assert(&dir.child("dst"), &[("f0", "f0"), ("keep.swp", "keep")]);
+        for name in [".dvignore", "f0.swp", "build"] {
+            assert!(!dir.child("dst").child(name).path().exists(), "{}", name);
+        }
+    }
+    #[tokio::test]
     async fn test_donothing() {
         let (dv, dir) = tenv(&[("f0", "f0"), ("f1", "f1")], &[]).await;
         let mut ctx = CopyContext::new(dv.context(), "this", "this", Some("y")).unwrap();
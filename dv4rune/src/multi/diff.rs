@@ -0,0 +1,86 @@
+//! Rendering of the differences between two versions of a file for the terminal.
+
+use crossterm::style::Stylize;
+use similar::{ChangeTag, TextDiff};
+
+use super::util::digest_bytes;
+
+/// How much of the beginning is searched for NUL to tell binary files apart, like git does.
+const SNIFF_LEN: usize = 8000;
+
+fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(SNIFF_LEN)].contains(&0) || std::str::from_utf8(content).is_err()
+}
+
+/// A colored unified diff from `old` to `new`, binary files are only summarized.
+pub fn render(old_name: &str, old: &[u8], new_name: &str, new: &[u8]) -> String {
+    if is_binary(old) || is_binary(new) {
+        let summary = |name: &str, content: &[u8]| {
+            format!(
+                "{}: {} bytes, sha256 {}",
+                name,
+                content.len(),
+                digest_bytes(content)
+            )
+        };
+        return format!(
+            "binary files differ\n{}\n{}",
+            summary(old_name, old),
+            summary(new_name, new)
+        );
+    }
+    //NOTE:checked above
+    let (old, new) = (
+        std::str::from_utf8(old).unwrap_or_default(),
+        std::str::from_utf8(new).unwrap_or_default(),
+    );
+    let diff = TextDiff::from_lines(old, new);
+    let mut out = String::new();
+    out.push_str(&format!("--- {}\n", old_name).bold().to_string());
+    out.push_str(&format!("+++ {}\n", new_name).bold().to_string());
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        out.push_str(&hunk.header().to_string().cyan().to_string());
+        out.push('\n');
+        for change in hunk.iter_changes() {
+            let mut line = match change.tag() {
+                ChangeTag::Delete => format!("-{}", change.value()),
+                ChangeTag::Insert => format!("+{}", change.value()),
+                ChangeTag::Equal => format!(" {}", change.value()),
+            };
+            if change.missing_newline() {
+                line.push_str("\n\\ No newline at end of file\n");
+            }
+            let line = match change.tag() {
+                ChangeTag::Delete => line.red().to_string(),
+                ChangeTag::Insert => line.green().to_string(),
+                ChangeTag::Equal => line,
+            };
+            out.push_str(&line);
+        }
+    }
+    if out.ends_with('\n') {
+        out.pop();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_diff() {
+        let out = render("a", b"1\n2\n3\n", "b", b"1\n4\n3\n");
+        assert!(out.contains("-2"), "{}", out);
+        assert!(out.contains("+4"), "{}", out);
+        assert!(out.contains("@@ -1,3 +1,3 @@"), "{}", out);
+    }
+
+    #[test]
+    fn binary_summary() {
+        let out = render("a", b"\x00\x01", "b", b"text");
+        assert!(out.starts_with("binary files differ"), "{}", out);
+        assert!(out.contains("a: 2 bytes"), "{}", out);
+        assert!(out.contains("b: 4 bytes"), "{}", out);
+    }
+}
@@ -1,31 +1,439 @@
+use std::{collections::HashMap, time::Duration};
+
 use super::dev::*;
-use dv_api::process::Script;
+use dv_api::{
+    process::{ExecOptions, Script},
+    user::User,
+};
+
+/// Options of `Dv::exec_with`, the timeout is given in seconds.
+///
+/// The guards work like those of Ansible's `command`, they are checked on the target user
+/// before running and the commands are skipped if any of them fails.
+/// In dry-run mode `unless` and `onlyif` only run if `read_only_guards` is set.
+#[derive(Debug, Default, rune::Any)]
+pub struct ExecOpts {
+    #[rune(get, set)]
+    cwd: Option<String>,
+    env: HashMap<String, String>,
+    #[rune(get, set)]
+    timeout: Option<u64>,
+    /// skip if the path exists
+    #[rune(get, set)]
+    creates: Option<String>,
+    /// skip if the path does not exist
+    #[rune(get, set)]
+    removes: Option<String>,
+    /// skip if the command succeeds
+    #[rune(get, set)]
+    unless: Option<String>,
+    /// skip if the command fails
+    #[rune(get, set)]
+    onlyif: Option<String>,
+    /// the `unless` and `onlyif` commands change nothing and may run in dry-run mode
+    #[rune(get, set)]
+    read_only_guards: bool,
+    /// log the output lines as they arrive instead of attaching the commands to the terminal,
+    /// for long unattended commands
+    #[rune(get, set)]
+    stream: bool,
+}
+
+/// What the guards decide about the commands.
+#[derive(Debug, PartialEq)]
+enum Guard {
+    Run,
+    Skip(String),
+    /// a guard command was not run in dry-run mode
+    WouldCheck(String),
+}
+
+impl Guard {
+    fn runs(&self) -> bool {
+        !matches!(self, Guard::Skip(_))
+    }
+
+    /// The suffix of the action log.
+    fn note(&self) -> String {
+        match self {
+            Guard::Run => String::new(),
+            Guard::Skip(reason) => format!(", {}", reason),
+            Guard::WouldCheck(cmd) => format!(", would check {}", cmd),
+        }
+    }
+}
+
+impl std::fmt::Display for ExecOpts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(cwd) = &self.cwd {
+            write!(f, "in {} ", cwd)?;
+        }
+        //NOTE:the values may be secrets, only the names are shown
+        if !self.env.is_empty() {
+            let mut keys: Vec<_> = self.env.keys().map(String::as_str).collect();
+            keys.sort_unstable();
+            write!(f, "env=[{}] ", keys.join(","))?;
+        }
+        if let Some(timeout) = self.timeout {
+            write!(f, "within {}s ", timeout)?;
+        }
+        Ok(())
+    }
+}
+
+impl ExecOpts {
+    pub fn as_options(&self) -> ExecOptions {
+        ExecOptions {
+            cwd: self.cwd.clone(),
+            env: self.env.clone(),
+            timeout: self.timeout.map(Duration::from_secs),
+        }
+    }
+
+    /// Check the guards, the guard commands run with the same options.
+    /// The paths are only inspected, but the commands may change anything, so in dry-run mode
+    /// they are reported instead of run unless `read_only_guards` is set.
+    async fn guard(&self, user: &User, options: &ExecOptions, dry_run: bool) -> LRes<Guard> {
+        if let Some(path) = &self.creates
+            && exists(user, path).await?
+        {
+            return Ok(Guard::Skip(format!("{} exists", path)));
+        }
+        if let Some(path) = &self.removes
+            && !exists(user, path).await?
+        {
+            return Ok(Guard::Skip(format!("{} does not exist", path)));
+        }
+        if dry_run && !self.read_only_guards {
+            let cmds: Vec<_> = [&self.unless, &self.onlyif].into_iter().flatten().collect();
+            return Ok(if cmds.is_empty() {
+                Guard::Run
+            } else {
+                Guard::WouldCheck(
+                    cmds.iter()
+                        .map(|c| c.as_str())
+                        .collect::<Vec<_>>()
+                        .join(" and "),
+                )
+            });
+        }
+        if let Some(cmd) = &self.unless
+            && user.exec_with(Script::Whole(cmd), options).await?.code == 0
+        {
+            return Ok(Guard::Skip(format!("{} succeeded", cmd)));
+        }
+        if let Some(cmd) = &self.onlyif
+            && user.exec_with(Script::Whole(cmd), options).await?.code != 0
+        {
+            return Ok(Guard::Skip(format!("{} failed", cmd)));
+        }
+        Ok(Guard::Run)
+    }
+
+    #[rune::function(path = Self::new)]
+    pub fn new() -> ExecOpts {
+        ExecOpts::default()
+    }
+
+    #[rune::function(path = Self::index_set, protocol = INDEX_SET)]
+    pub fn index_set(&mut self, key: &str, value: &str) {
+        self.env.insert(key.to_string(), value.to_string());
+    }
+}
+
+/// Run `commands` as a script of `shell`, e.g. `bash` or an interpreter path,
+/// or as a command line of the user's shell if none is given.
+fn script<'a>(shell: Option<&str>, commands: &'a str) -> Script<'a, 'a> {
+    match shell {
+        Some(shell) => Script::Script {
+            executor: shell.into(),
+            input: Box::new([commands].into_iter()),
+        },
+        None => Script::Whole(commands),
+    }
+}
 
 pub async fn exec(
     ctx: &Context<'_>,
     uid: impl AsRef<str>,
     shell: Option<&str>,
     commands: impl AsRef<str>,
+    opts: &ExecOpts,
 ) -> LRes<bool> {
     let uid = uid.as_ref();
     let commands = commands.as_ref();
-    let script = shell
-        .map(|_sh| Script::sh(Box::new([commands].into_iter())))
-        .unwrap_or_else(|| Script::Whole(commands));
+    let script = script(shell, commands);
     let user = ctx.get_user(uid)?;
+    let options = opts.as_options();
+    let guard = opts
+        .guard(user, &options, ctx.dry_run)
+        .log(ctx.interactor)
+        .await?;
+    if !guard.runs() {
+        action!(ctx, false, "exec {}{}{}", opts, commands, guard.note());
+        return Ok(false);
+    }
     if !ctx.dry_run {
-        let pp = user
-            .pty(script, ctx.interactor.window_size().await)
-            .log(ctx.interactor)
-            .await?;
-
-        let ec = ctx.interactor.ask(pp).log(ctx.interactor).await?;
+        let ec = if opts.stream {
+            user.exec_stream(script, &options, ctx.interactor)
+                .log(ctx.interactor)
+                .await?
+                .code
+        } else {
+            let pp = user
+                .pty_with(script, ctx.interactor.window_size().await, &options)
+                .log(ctx.interactor)
+                .await?;
+            ctx.interactor.ask(pp).log(ctx.interactor).await?
+        };
         if ec != 0 {
             let msg = format!("unexpect exit code: {}", ec);
             ctx.interactor.log(msg.clone()).await;
             Err(rune::support::Error::msg(msg))?
         }
     }
-    action!(ctx, true, "exec {}", commands);
+    action!(ctx, true, "exec {}{}{}", opts, commands, guard.note());
     Ok(true)
 }
+
+async fn exists(user: &User, path: &str) -> LRes<bool> {
+    let (_, res) = user.check_file(path.into()).await;
+    match res {
+        Ok(_) => Ok(true),
+        Err(e) if e.is_not_found() => Ok(false),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// The result of `Dv::capture`, the outputs are decoded lossily.
+#[derive(Debug, Default, rune::Any)]
+pub struct Captured {
+    /// `None` if the commands did not run in dry-run mode
+    #[rune(get)]
+    code: Option<i32>,
+    #[rune(get)]
+    stdout: String,
+    #[rune(get)]
+    stderr: String,
+}
+
+impl Captured {
+    #[rune::function(instance)]
+    fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    #[rune::function(instance)]
+    fn skipped(&self) -> bool {
+        self.code.is_none()
+    }
+}
+
+/// Run `commands` and collect the outputs instead of attaching them to the terminal.
+/// In dry-run mode only `read_only` commands run, the others are skipped without a code.
+pub async fn capture(
+    ctx: &Context<'_>,
+    uid: impl AsRef<str>,
+    shell: Option<&str>,
+    commands: impl AsRef<str>,
+    read_only: bool,
+) -> LRes<Captured> {
+    let uid = uid.as_ref();
+    let commands = commands.as_ref();
+    let script = script(shell, commands);
+    let user = ctx.get_user(uid)?;
+    let run = !ctx.dry_run || read_only;
+    let captured = if run {
+        let output = user.exec(script).log(ctx.interactor).await?;
+        Captured {
+            code: Some(output.code),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }
+    } else {
+        Captured::default()
+    };
+    action!(ctx, run, "capture {}", commands);
+    Ok(captured)
+}
+
+pub fn register(m: &mut rune::module::Module) -> Result<(), rune::ContextError> {
+    m.ty::<ExecOpts>()?;
+    m.function_meta(ExecOpts::new)?;
+    m.function_meta(ExecOpts::index_set)?;
+    m.ty::<Captured>()?;
+    m.function_meta(Captured::success)?;
+    m.function_meta(Captured::skipped)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use assert_fs::{TempDir, prelude::*};
+    use dv_api::{
+        process::ExecOptions,
+        user::{Config, Keyring, User},
+    };
+
+    use crate::{cache::SqliteCache, dv::tests::TestDv, interactor::TermInteractor};
+
+    use super::{ExecOpts, Guard, capture, exec};
+
+    async fn this() -> User {
+        let int = TermInteractor::new().unwrap();
+        Config::default()
+            .connect(None, &int, &Arc::<Keyring>::default())
+            .await
+            .unwrap()
+    }
+
+    async fn guard(opts: &ExecOpts, dry_run: bool) -> Guard {
+        let user = this().await;
+        opts.guard(&user, &ExecOptions::default(), dry_run)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn path_guards() {
+        let dir = TempDir::new().unwrap();
+        let file = dir.child("file");
+        file.touch().unwrap();
+        let file = file.to_string_lossy().into_owned();
+        let missing = dir.child("missing").to_string_lossy().into_owned();
+        for dry_run in [false, true] {
+            let opts = ExecOpts {
+                creates: Some(file.clone()),
+                ..Default::default()
+            };
+            assert_eq!(
+                guard(&opts, dry_run).await,
+                Guard::Skip(format!("{} exists", file))
+            );
+            let opts = ExecOpts {
+                creates: Some(missing.clone()),
+                ..Default::default()
+            };
+            assert_eq!(guard(&opts, dry_run).await, Guard::Run);
+            let opts = ExecOpts {
+                removes: Some(missing.clone()),
+                ..Default::default()
+            };
+            assert_eq!(
+                guard(&opts, dry_run).await,
+                Guard::Skip(format!("{} does not exist", missing))
+            );
+        }
+    }
+
+    #[test]
+    fn display_hides_env_values() {
+        let opts = ExecOpts {
+            cwd: Some("/tmp".to_string()),
+            env: HashMap::from([
+                ("TOKEN".to_string(), "secret".to_string()),
+                ("A".to_string(), "1".to_string()),
+            ]),
+            timeout: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(opts.to_string(), "in /tmp env=[A,TOKEN] within 5s ");
+    }
+
+    #[tokio::test]
+    async fn command_guards() {
+        let unless = |cmd: &str| ExecOpts {
+            unless: Some(cmd.to_string()),
+            ..Default::default()
+        };
+        let onlyif = |cmd: &str| ExecOpts {
+            onlyif: Some(cmd.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            guard(&unless("true"), false).await,
+            Guard::Skip("true succeeded".into())
+        );
+        assert_eq!(guard(&unless("false"), false).await, Guard::Run);
+        assert_eq!(
+            guard(&onlyif("false"), false).await,
+            Guard::Skip("false failed".into())
+        );
+        assert_eq!(guard(&onlyif("true"), false).await, Guard::Run);
+    }
+
+    #[tokio::test]
+    async fn dry_run_command_guards() {
+        let dir = TempDir::new().unwrap();
+        let mark = dir.child("mark");
+        let cmd = format!("touch {}", mark.to_string_lossy());
+        let opts = ExecOpts {
+            unless: Some(cmd.clone()),
+            onlyif: Some("true".into()),
+            ..Default::default()
+        };
+        let res = guard(&opts, true).await;
+        assert_eq!(res, Guard::WouldCheck(format!("{} and true", cmd)));
+        assert!(res.runs());
+        assert_eq!(res.note(), format!(", would check {} and true", cmd));
+        assert!(!mark.exists(), "guard ran in dry-run mode");
+
+        let opts = ExecOpts {
+            unless: Some("true".into()),
+            read_only_guards: true,
+            ..Default::default()
+        };
+        let res = guard(&opts, true).await;
+        assert!(!res.runs());
+        assert_eq!(res.note(), ", true succeeded");
+    }
+
+    #[tokio::test]
+    async fn stream_lines_before_exit() {
+        let (int, mut logs) = TermInteractor::with_logs();
+        let dv = TestDv {
+            dry_run: false,
+            users: HashMap::from([("this".to_string(), this().await)]),
+            cache: SqliteCache::memory(),
+            interactor: int,
+        };
+        let ctx = dv.context();
+        let opts = ExecOpts {
+            stream: true,
+            ..Default::default()
+        };
+        let run = exec(&ctx, "this", None, "echo first; sleep 3", &opts);
+        tokio::pin!(run);
+        let seen = async {
+            while !logs.take().iter().any(|l| l == "first") {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        };
+        tokio::select! {
+            _ = &mut run => panic!("the line arrived only after exit"),
+            res = tokio::time::timeout(Duration::from_secs(2), seen) => res.expect("no line within 2s"),
+        }
+        assert!(run.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn capture_dry_run() {
+        let (int, mut logs) = TermInteractor::with_logs();
+        let dv = TestDv {
+            dry_run: true,
+            users: HashMap::from([("this".to_string(), this().await)]),
+            cache: SqliteCache::memory(),
+            interactor: int,
+        };
+        let ctx = dv.context();
+        let captured = capture(&ctx, "this", None, "echo hi", false).await.unwrap();
+        assert!(captured.skipped() && !captured.success());
+        assert_eq!(logs.take(), ["[n] skip capture echo hi"]);
+        let captured = capture(&ctx, "this", None, "echo hi", true).await.unwrap();
+        assert_eq!(captured.code, Some(0));
+        assert_eq!(captured.stdout, "hi\n");
+        assert_eq!(logs.take(), ["[n] exec capture echo hi"]);
+    }
+}
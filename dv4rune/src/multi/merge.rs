@@ -0,0 +1,119 @@
+//! Line-based three-way merge of two versions of a file against their common base.
+
+use similar::{Algorithm, DiffOp, capture_diff_slices};
+
+pub struct Merged {
+    pub text: String,
+    /// the number of hunks changed differently on both sides, marked in `text`
+    pub conflicts: usize,
+}
+
+/// For each line of `base`, the line of `other` it is kept as, if any.
+fn matches(base: &[&str], other: &[&str]) -> Vec<Option<usize>> {
+    let mut matched = vec![None; base.len()];
+    for op in capture_diff_slices(Algorithm::Myers, base, other) {
+        if let DiffOp::Equal {
+            old_index,
+            new_index,
+            len,
+        } = op
+        {
+            for i in 0..len {
+                matched[old_index + i] = Some(new_index + i);
+            }
+        }
+    }
+    matched
+}
+
+fn push_lines(text: &mut String, lines: &[&str]) {
+    for line in lines {
+        text.push_str(line);
+    }
+    //NOTE:a marker must start on its own line
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+/// Merge the changes from `base` to `ours` and to `theirs` like diff3, the lines changed on
+/// both sides in different ways are put between conflict markers naming each side.
+pub fn merge3(base: &str, ours: &str, theirs: &str, ours_name: &str, theirs_name: &str) -> Merged {
+    let base = base.split_inclusive('\n').collect::<Vec<_>>();
+    let ours = ours.split_inclusive('\n').collect::<Vec<_>>();
+    let theirs = theirs.split_inclusive('\n').collect::<Vec<_>>();
+    let (to_ours, to_theirs) = (matches(&base, &ours), matches(&base, &theirs));
+    let mut text = String::new();
+    let mut conflicts = 0;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        //NOTE:a line kept on both sides is stable, the chunks between them are compared
+        if i < base.len() && to_ours[i] == Some(j) && to_theirs[i] == Some(k) {
+            text.push_str(base[i]);
+            (i, j, k) = (i + 1, j + 1, k + 1);
+            continue;
+        }
+        let next = (i..base.len()).find_map(|b| Some((b, to_ours[b]?, to_theirs[b]?)));
+        let (b, x, y) = next.unwrap_or((base.len(), ours.len(), theirs.len()));
+        let (base_chunk, ours_chunk, theirs_chunk) = (&base[i..b], &ours[j..x], &theirs[k..y]);
+        if ours_chunk == base_chunk {
+            text.extend(theirs_chunk.iter().copied());
+        } else if theirs_chunk == base_chunk || ours_chunk == theirs_chunk {
+            text.extend(ours_chunk.iter().copied());
+        } else {
+            conflicts += 1;
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            text.push_str(&format!("<<<<<<< {}\n", ours_name));
+            push_lines(&mut text, ours_chunk);
+            text.push_str("=======\n");
+            push_lines(&mut text, theirs_chunk);
+            text.push_str(&format!(">>>>>>> {}\n", theirs_name));
+        }
+        (i, j, k) = (b, x, y);
+        if next.is_none() {
+            break;
+        }
+    }
+    Merged { text, conflicts }
+}
+
+/// Whether `text` still has conflict markers left by `merge3`.
+pub fn has_markers(text: &str) -> bool {
+    text.lines()
+        .any(|line| line.starts_with("<<<<<<< ") || line.starts_with(">>>>>>> "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_merge() {
+        let base = "a\nb\nc\nd\n";
+        let ours = "a\nB\nc\nd\n";
+        let theirs = "a\nb\nc\nD\ne\n";
+        let merged = merge3(base, ours, theirs, "ours", "theirs");
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(merged.text, "a\nB\nc\nD\ne\n");
+    }
+
+    #[test]
+    fn same_change() {
+        let merged = merge3("a\nb\n", "a\nc\n", "a\nc\n", "ours", "theirs");
+        assert_eq!(merged.conflicts, 0);
+        assert_eq!(merged.text, "a\nc\n");
+    }
+
+    #[test]
+    fn conflict() {
+        let merged = merge3("a\nb\nc\n", "a\nx\nc\n", "a\ny\nc\n", "ours", "theirs");
+        assert_eq!(merged.conflicts, 1);
+        assert_eq!(
+            merged.text,
+            "a\n<<<<<<< ours\nx\n=======\ny\n>>>>>>> theirs\nc\n"
+        );
+        assert!(has_markers(&merged.text));
+    }
+}
@@ -1,7 +1,54 @@
 use dv_api::{fs::OpenFlags, user::User, util::XPath, whatever};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{info, trace, warn};
 
 use super::dev::LRes;
+
+/// The sha256 of a file in hex, the content is streamed from wherever the user lives.
+pub async fn digest(user: &User, path: &XPath) -> LRes<String> {
+    let mut file = user.open(path, OpenFlags::READ).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// The sha256 of `content` in hex, like `digest`.
+pub fn digest_bytes(content: &[u8]) -> String {
+    to_hex(&Sha256::digest(content))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub async fn read_all(user: &User, path: &XPath) -> LRes<Vec<u8>> {
+    let mut file = user.open(path, OpenFlags::READ).await?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content).await?;
+    Ok(content)
+}
+
+/// Replace the content of a file, creating it if missing.
+pub async fn write_all(user: &User, path: &XPath, content: &[u8]) -> LRes<()> {
+    let mut file = user
+        .open(
+            path,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+        )
+        .await?;
+    file.write_all(content).await?;
+    file.shutdown().await?;
+    Ok(())
+}
+
 pub async fn try_copy(
     src: &User,
     src_uid: &str,
@@ -48,6 +95,7 @@ pub async fn try_copy(
             )
             .await?;
         tokio::io::copy(&mut src, &mut dst).await?;
+        dst.shutdown().await?;
     }
     Ok(())
 }
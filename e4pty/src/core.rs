@@ -1,9 +1,13 @@
-use std::{fmt::Display, io::Write, process::Command};
+use std::{
+    borrow::Cow, collections::HashMap, fmt::Display, io::Write, process::Command, time::Duration,
+};
 
 use async_trait::async_trait;
+use tempfile::TempPath;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::debug;
 
-use crate::Result;
+use crate::{Result, quote};
 
 #[derive(Debug, Clone)]
 pub struct WindowSize {
@@ -22,9 +26,50 @@ pub trait PtyReader: AsyncRead {}
 
 pub type BoxedPtyReader = Box<dyn PtyReader + Send + Sync + Unpin>;
 
+/// The signals a caller may send, each platform delivers them as close as it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Hup,
+    Int,
+    Kill,
+    Term,
+}
+
+impl Signal {
+    /// The number of the signal on POSIX systems.
+    pub fn number(self) -> i32 {
+        match self {
+            Signal::Hup => 1,
+            Signal::Int => 2,
+            Signal::Kill => 9,
+            Signal::Term => 15,
+        }
+    }
+}
+
+impl Display for Signal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Signal::Hup => "HUP",
+            Signal::Int => "INT",
+            Signal::Kill => "KILL",
+            Signal::Term => "TERM",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[async_trait]
 pub trait PtyCtl {
     async fn wait(&mut self) -> Result<i32>;
+    /// The exit code if the process has ended, without waiting for it.
+    async fn try_wait(&mut self) -> Result<Option<i32>> {
+        Err(unsupported("try_wait"))?
+    }
+    /// Send `sig` to the process, or to its process group where there is one.
+    async fn signal(&mut self, _sig: Signal) -> Result<()> {
+        Err(unsupported("signal"))?
+    }
 }
 
 pub type BoxedPtyCtl = Box<dyn PtyCtl + Send + Sync + Unpin>;
@@ -50,27 +95,180 @@ impl BoxedPty {
     pub fn destruct(self) -> (BoxedPtyCtl, BoxedPtyWriter, BoxedPtyReader) {
         (self.ctl, self.writer, self.reader)
     }
+    /// Keep `guard` until the process exits or the ctl is dropped, to release what the process
+    /// needs once it is done with.
+    pub fn with_guard(self, guard: impl Send + Sync + Unpin + 'static) -> Self {
+        Self {
+            ctl: Box::new(GuardCtl {
+                inner: self.ctl,
+                guard: Some(guard),
+            }),
+            ..self
+        }
+    }
+    /// Make `wait` kill the process and fail once `timeout` expires, a process which can't be
+    /// signaled is expected to end when the pty is dropped.
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        let Some(timeout) = timeout else {
+            return self;
+        };
+        Self {
+            ctl: Box::new(TimeoutCtl {
+                inner: self.ctl,
+                deadline: tokio::time::Instant::now() + timeout,
+            }),
+            ..self
+        }
+    }
+}
+
+struct GuardCtl<G> {
+    inner: BoxedPtyCtl,
+    guard: Option<G>,
+}
+
+#[async_trait]
+impl<G: Send + Sync + Unpin> PtyCtl for GuardCtl<G> {
+    async fn wait(&mut self) -> Result<i32> {
+        let code = self.inner.wait().await?;
+        self.guard = None;
+        Ok(code)
+    }
+    async fn try_wait(&mut self) -> Result<Option<i32>> {
+        let code = self.inner.try_wait().await?;
+        if code.is_some() {
+            self.guard = None;
+        }
+        Ok(code)
+    }
+    async fn signal(&mut self, sig: Signal) -> Result<()> {
+        self.inner.signal(sig).await
+    }
+}
+
+struct TimeoutCtl {
+    inner: BoxedPtyCtl,
+    deadline: tokio::time::Instant,
+}
+
+#[async_trait]
+impl PtyCtl for TimeoutCtl {
+    async fn wait(&mut self) -> Result<i32> {
+        match tokio::time::timeout_at(self.deadline, self.inner.wait()).await {
+            Ok(res) => res,
+            Err(_) => {
+                if let Err(e) = self.inner.signal(Signal::Kill).await {
+                    debug!("kill on timeout failed: {}", e);
+                }
+                Err(timed_out())?
+            }
+        }
+    }
+    async fn try_wait(&mut self) -> Result<Option<i32>> {
+        self.inner.try_wait().await
+    }
+    async fn signal(&mut self, sig: Signal) -> Result<()> {
+        self.inner.signal(sig).await
+    }
+}
+
+pub(crate) fn timed_out() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::TimedOut, "process timed out")
+}
+
+pub(crate) fn unsupported(op: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!("{} is unsupported", op),
+    )
 }
 
+/// The interpreter of a script, shells are also used to run command lines remotely.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ScriptExecutor {
     Sh,
+    Bash,
+    Zsh,
+    Fish,
+    Python3,
     Powershell,
+    /// the path or name of any other interpreter, which is called with the script as argument
+    Custom(String),
 }
 
 impl Display for ScriptExecutor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            ScriptExecutor::Sh => write!(f, "sh"),
-            ScriptExecutor::Powershell => write!(f, "powershell"),
+        write!(f, "{}", self.as_ref())
+    }
+}
+
+impl From<&str> for ScriptExecutor {
+    fn from(value: &str) -> Self {
+        match value {
+            "sh" => ScriptExecutor::Sh,
+            "bash" => ScriptExecutor::Bash,
+            "zsh" => ScriptExecutor::Zsh,
+            "fish" => ScriptExecutor::Fish,
+            "python3" | "python" => ScriptExecutor::Python3,
+            "powershell" => ScriptExecutor::Powershell,
+            _ => ScriptExecutor::Custom(value.to_string()),
         }
     }
 }
 
 impl ScriptExecutor {
+    /// Quote `word` so that the shell reads it back as a single literal argument,
+    /// interpreters which are not shells get it quoted for sh, as they are started from it.
+    pub fn quote<'a>(&self, word: &'a str) -> Cow<'a, str> {
+        match self {
+            ScriptExecutor::Powershell => quote::powershell(word),
+            ScriptExecutor::Fish => quote::fish(word),
+            _ => quote::sh(word),
+        }
+    }
+    /// Join `program` and `args` into a command line for the shell, `program` is taken as
+    /// a native one by PowerShell.
+    pub fn join<'a>(&self, program: &str, args: impl IntoIterator<Item = &'a str>) -> String {
+        let mut cmd = match self {
+            //NOTE:a quoted program is a string to PowerShell unless invoked by `&`
+            ScriptExecutor::Powershell => format!("& {}", self.quote(program)),
+            _ => self.quote(program).into_owned(),
+        };
+        for arg in args {
+            cmd.push(' ');
+            match self {
+                ScriptExecutor::Powershell => cmd.push_str(&quote::powershell_native(arg)),
+                _ => cmd.push_str(&self.quote(arg)),
+            }
+        }
+        cmd
+    }
+    /// The suffix of the script file, some interpreters refuse or treat differently files
+    /// without the usual one.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            ScriptExecutor::Sh | ScriptExecutor::Bash | ScriptExecutor::Zsh => ".sh",
+            ScriptExecutor::Fish => ".fish",
+            ScriptExecutor::Python3 => ".py",
+            ScriptExecutor::Powershell => ".ps1",
+            ScriptExecutor::Custom(_) => "",
+        }
+    }
+    /// The lines to put in front of a script so that it removes itself.
+    ///
+    /// The interpreters either parse the whole file upfront or keep it open,
+    /// so the file may go away before the script ends.
     pub fn prepare_clean(&self) -> Vec<u8> {
         match self {
-            ScriptExecutor::Sh => b"\ntrap 'rm -f -- \"$0\"' EXIT;".to_vec(),
-            ScriptExecutor::Powershell => b"\r\nRemove-Item $MyInvocation.MyCommand.Path".to_vec(),
+            ScriptExecutor::Sh | ScriptExecutor::Bash | ScriptExecutor::Zsh => {
+                b"trap 'rm -f -- \"$0\"' EXIT\n".to_vec()
+            }
+            ScriptExecutor::Fish => b"rm -f -- (status filename)\n".to_vec(),
+            ScriptExecutor::Powershell => b"Remove-Item $MyInvocation.MyCommand.Path\r\n".to_vec(),
+            //NOTE:a line in front of a python script would displace `from __future__` imports and
+            //the coding declaration, and the syntax of a custom one is unknown, so these files are
+            //left to the caller
+            ScriptExecutor::Python3 | ScriptExecutor::Custom(_) => Vec::new(),
         }
     }
 }
@@ -79,11 +277,76 @@ impl AsRef<str> for ScriptExecutor {
     fn as_ref(&self) -> &str {
         match self {
             ScriptExecutor::Sh => "sh",
+            ScriptExecutor::Bash => "bash",
+            ScriptExecutor::Zsh => "zsh",
+            ScriptExecutor::Fish => "fish",
+            ScriptExecutor::Python3 => "python3",
             ScriptExecutor::Powershell => "powershell",
+            ScriptExecutor::Custom(program) => program,
         }
     }
 }
 
+/// How to run a script, the defaults run it as is.
+#[derive(Debug, Clone, Default)]
+pub struct ExecOptions {
+    /// the working directory, or where the user's processes start by default
+    pub cwd: Option<String>,
+    /// extra environment variables
+    pub env: HashMap<String, String>,
+    /// kill the process when it expires
+    pub timeout: Option<Duration>,
+}
+
+impl ExecOptions {
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.envs(&self.env);
+    }
+    /// Prefix a command line for `shell`, for the cases where the process is not spawned locally.
+    pub fn wrap(&self, shell: &ScriptExecutor, cmd: String) -> std::io::Result<String> {
+        if self.cwd.is_none() && self.env.is_empty() {
+            return Ok(cmd);
+        }
+        let mut prefix = String::new();
+        if let Some(cwd) = &self.cwd {
+            let cwd = shell.quote(cwd);
+            match shell {
+                ScriptExecutor::Powershell => prefix.push_str(&format!(
+                    "Set-Location -LiteralPath {} -ErrorAction Stop; ",
+                    cwd
+                )),
+                ScriptExecutor::Fish => prefix.push_str(&format!("cd -- {}; or exit 1; ", cwd)),
+                _ => prefix.push_str(&format!("cd -- {} || exit 1; ", cwd)),
+            }
+        }
+        let mut env = self.env.iter().collect::<Vec<_>>();
+        env.sort();
+        for (key, value) in env {
+            if key.is_empty()
+                || key.starts_with(|c: char| c.is_ascii_digit())
+                || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid environment variable name {}", key),
+                ));
+            }
+            let value = shell.quote(value);
+            match shell {
+                ScriptExecutor::Powershell => {
+                    prefix.push_str(&format!("$env:{} = {}; ", key, value))
+                }
+                ScriptExecutor::Fish => prefix.push_str(&format!("set -gx {} {}; ", key, value)),
+                _ => prefix.push_str(&format!("export {}={}; ", key, value)),
+            }
+        }
+        Ok(prefix + &cmd)
+    }
+}
+
 pub enum Script<'a, 'b> {
     Whole(&'a str),
     Split {
@@ -109,13 +372,23 @@ impl<'a, 'b> Script<'a, 'b> {
             input,
         }
     }
-    pub fn into_command(self) -> std::io::Result<Command> {
+    /// The command to spawn, and the script file it runs if any, which is removed when dropped
+    /// and so must be kept until the process exits.
+    pub fn into_command(self) -> std::io::Result<(Command, Option<TempPath>)> {
+        let mut script = None;
         let cmd = match self {
+            //NOTE:a whole command line is left to the shell, like ssh does
+            #[cfg(not(windows))]
             Script::Whole(cmd) => {
-                let mut iter = cmd.split_whitespace();
-                let mut cmd = Command::new(iter.next().unwrap());
-                cmd.args(iter);
-                cmd
+                let mut builder = Command::new("sh");
+                builder.arg("-c").arg(cmd);
+                builder
+            }
+            #[cfg(windows)]
+            Script::Whole(cmd) => {
+                let mut builder = Command::new("powershell");
+                builder.args(["-NoProfile", "-Command", cmd]);
+                builder
             }
             Script::Split { program, args } => {
                 let mut cmd = Command::new(program);
@@ -123,21 +396,18 @@ impl<'a, 'b> Script<'a, 'b> {
                 cmd
             }
             Script::Script { executor, input } => {
-                let mut temp = match executor {
-                    ScriptExecutor::Sh => tempfile::NamedTempFile::new(),
-                    ScriptExecutor::Powershell => tempfile::NamedTempFile::with_suffix(".ps1"),
-                }?;
+                let mut temp = tempfile::NamedTempFile::with_suffix(executor.suffix())?;
                 for line in input {
                     temp.write_all(line.as_bytes())?;
                 }
-                temp.write_all(executor.prepare_clean().as_slice())?;
-                let path = temp.into_temp_path().keep()?;
+                let path = temp.into_temp_path();
                 let mut cmd = Command::new(executor.as_ref());
-                cmd.arg(path);
+                cmd.arg(&path);
+                script = Some(path);
                 cmd
             }
         };
-        Ok(cmd)
+        Ok((cmd, script))
     }
 }
 
@@ -168,3 +438,49 @@ impl<'a, 'b> Script<'a, 'b> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    struct Exited;
+
+    #[async_trait]
+    impl PtyCtl for Exited {
+        async fn wait(&mut self) -> Result<i32> {
+            Ok(0)
+        }
+    }
+
+    #[tokio::test]
+    async fn guard_released_on_exit() {
+        let guard = Arc::new(());
+        let mut ctl = GuardCtl {
+            inner: Box::new(Exited),
+            guard: Some(guard.clone()),
+        };
+        assert_eq!(Arc::strong_count(&guard), 2);
+        assert_eq!(ctl.wait().await.unwrap(), 0);
+        assert_eq!(Arc::strong_count(&guard), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn script_removed_after_use() {
+        let script = Script::Script {
+            executor: ScriptExecutor::Custom("sh".to_string()),
+            input: Box::new(["echo \"$0\""].into_iter()),
+        };
+        let (mut cmd, script) = script.into_command().unwrap();
+        let path = script.as_ref().unwrap().to_path_buf();
+        let output = cmd.output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            path.to_string_lossy()
+        );
+        drop(script);
+        assert!(!path.exists());
+    }
+}
@@ -2,22 +2,65 @@ use std::os::{fd::AsRawFd, unix::process::ExitStatusExt};
 
 use async_trait::async_trait;
 use rustix_openpty::rustix::termios::{self, Winsize};
-use tokio::fs::File;
+use tokio::{fs::File, time::Instant};
 
-use crate::{core::*, error::Result};
+use crate::{
+    core::{timed_out, *},
+    error::Result,
+};
 
 struct PtyCtlImpl {
-    child: std::process::Child,
+    /// reaped on `SIGCHLD` by tokio, so waiting does not block a worker
+    child: tokio::process::Child,
+    deadline: Option<Instant>,
+}
+
+fn exit_code(es: std::process::ExitStatus) -> i32 {
+    es.code()
+        .unwrap_or_else(|| es.signal().map_or(1, |v| 128 + v))
 }
 
 #[async_trait]
 impl PtyCtl for PtyCtlImpl {
     async fn wait(&mut self) -> Result<i32> {
-        let ec = self.child.wait().map(|es| {
-            es.code()
-                .unwrap_or_else(|| es.signal().map_or(1, |v| 128 + v))
-        })?;
-        Ok(ec)
+        let Some(deadline) = self.deadline else {
+            return Ok(exit_code(self.child.wait().await?));
+        };
+        match tokio::time::timeout_at(deadline, self.child.wait()).await {
+            Ok(es) => Ok(exit_code(es?)),
+            Err(_) => {
+                //NOTE:kill the whole group, background jobs would keep the pty open otherwise
+                self.signal(Signal::Kill).await?;
+                self.child.wait().await?;
+                Err(timed_out())?
+            }
+        }
+    }
+    async fn try_wait(&mut self) -> Result<Option<i32>> {
+        Ok(self.child.try_wait()?.map(exit_code))
+    }
+    async fn signal(&mut self, sig: Signal) -> Result<()> {
+        #[cfg(target_os = "macos")]
+        use rustix::process;
+        #[cfg(not(target_os = "macos"))]
+        use rustix_openpty::rustix::process;
+        let sig = match sig {
+            Signal::Hup => process::Signal::HUP,
+            Signal::Int => process::Signal::INT,
+            Signal::Kill => process::Signal::KILL,
+            Signal::Term => process::Signal::TERM,
+        };
+        let Some(pid) = self
+            .child
+            .id()
+            .and_then(|id| process::Pid::from_raw(id as i32))
+        else {
+            //NOTE:already reaped, there is nobody to signal
+            return Ok(());
+        };
+        //NOTE:the child leads its own session, so the group includes what it spawned
+        process::kill_process_group(pid, sig)?;
+        Ok(())
     }
 }
 
@@ -39,7 +82,11 @@ impl PtyWriter for File {
 
 impl PtyReader for File {}
 
-pub fn openpty(window_size: WindowSize, script: Script<'_, '_>) -> std::io::Result<BoxedPty> {
+pub fn openpty(
+    window_size: WindowSize,
+    script: Script<'_, '_>,
+    opts: &ExecOptions,
+) -> std::io::Result<BoxedPty> {
     let pair = rustix_openpty::openpty(
         None,
         Some(&Winsize {
@@ -56,7 +103,8 @@ pub fn openpty(window_size: WindowSize, script: Script<'_, '_>) -> std::io::Resu
         termios.input_modes.set(termios::InputModes::IUTF8, true);
         let _ = termios::tcsetattr(&pair.controller, termios::OptionalActions::Now, &termios);
     }
-    let mut builder = script.into_command()?;
+    let (mut builder, script) = script.into_command()?;
+    opts.apply(&mut builder);
     // Setup child stdin/stdout/stderr.
     builder.stdin(pair.user.try_clone()?);
     builder.stderr(pair.user.try_clone()?);
@@ -85,17 +133,80 @@ pub fn openpty(window_size: WindowSize, script: Script<'_, '_>) -> std::io::Resu
             Ok(())
         });
     }
-    // TODO:set working directory
-    // set signal handler
+    // TODO:set signal handler
 
-    let child = builder.spawn()?;
+    let child = tokio::process::Command::from(builder).spawn()?;
     use rustix_openpty::rustix::io;
     let pw = io::dup(&stdio)?;
     io::fcntl_setfd(&pw, io::fcntl_getfd(&pw)? | io::FdFlags::CLOEXEC)?;
     let pr = std::fs::File::from(stdio);
-    Ok(BoxedPty::new(
-        PtyCtlImpl { child },
+    let pty = BoxedPty::new(
+        PtyCtlImpl {
+            child,
+            deadline: opts.timeout.map(|timeout| Instant::now() + timeout),
+        },
         File::from_std(std::fs::File::from(pw)),
         File::from_std(pr),
-    ))
+    );
+    Ok(match script {
+        Some(script) => pty.with_guard(script),
+        None => pty,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    const SIZE: WindowSize = WindowSize { rows: 24, cols: 80 };
+
+    /// Run `cmd` and collect its output until the pty hangs up.
+    async fn run(cmd: &str, opts: &ExecOptions) -> (Result<i32>, String) {
+        let pty = openpty(SIZE, Script::Whole(cmd), opts).unwrap();
+        let (mut ctl, _writer, mut reader) = pty.destruct();
+        let mut output = Vec::new();
+        //NOTE:the controller fails with EIO once the child is gone
+        let _ = reader.read_to_end(&mut output).await;
+        (
+            ctl.wait().await,
+            String::from_utf8_lossy(&output).into_owned(),
+        )
+    }
+
+    #[tokio::test]
+    async fn cwd_and_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let cwd = dir.path().canonicalize().unwrap();
+        let opts = ExecOptions {
+            cwd: Some(cwd.to_string_lossy().into_owned()),
+            env: HashMap::from([("E4PTY_TEST".to_string(), "value".to_string())]),
+            timeout: None,
+        };
+        let (code, output) = run("echo \"$(pwd -P):$E4PTY_TEST\"", &opts).await;
+        assert_eq!(code.unwrap(), 0);
+        assert_eq!(output.trim(), format!("{}:value", cwd.display()));
+    }
+
+    #[tokio::test]
+    async fn timeout_kills() {
+        let opts = ExecOptions {
+            timeout: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let pty = openpty(SIZE, Script::Whole("sh -c 'sleep 5 & wait'"), &opts).unwrap();
+        let (mut ctl, _writer, mut reader) = pty.destruct();
+        let start = std::time::Instant::now();
+        let err = ctl.wait().await.unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        let mut output = Vec::new();
+        //NOTE:the controller fails with EIO once the group is gone
+        let _ = tokio::time::timeout(Duration::from_secs(3), reader.read_to_end(&mut output))
+            .await
+            .expect("the background sleep still holds the pty");
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
 }
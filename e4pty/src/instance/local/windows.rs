@@ -1,14 +1,17 @@
 use async_trait::async_trait;
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
 use std::io::{Error, Write};
+use std::iter::once;
 use std::mem;
 use std::os::windows::ffi::OsStrExt;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::debug;
 
-use windows::Win32::Foundation::{HANDLE, MAX_PATH};
+use windows::Win32::Foundation::{HANDLE, MAX_PATH, WAIT_TIMEOUT};
 use windows::Win32::Storage::FileSystem::{ReadFile, SearchPathW, WriteFile};
 use windows::Win32::System::Console::*;
 use windows::Win32::System::Pipes::CreatePipe;
@@ -16,7 +19,8 @@ use windows::Win32::System::Threading::*;
 use windows::core::*;
 
 use crate::Result;
-use crate::core::*;
+use crate::core::{timed_out, unsupported, *};
+use crate::quote;
 
 type ResizeFn = Box<dyn Send + Sync + Fn(HPCON, WindowSize) -> windows::core::Result<()>>;
 type CreateFn = Box<dyn Fn(WindowSize, HANDLE, HANDLE) -> windows::core::Result<HPCON>>;
@@ -131,6 +135,7 @@ pub struct PtyCtlImpl {
     pub hpcon: HPCON,
     pub handle: HANDLE,
     pub close: CloseFn,
+    deadline: Option<Instant>,
 }
 
 impl Drop for PtyCtlImpl {
@@ -147,11 +152,39 @@ unsafe impl Sync for PtyCtlImpl {}
 #[async_trait]
 impl PtyCtl for PtyCtlImpl {
     async fn wait(&mut self) -> Result<i32> {
+        //NOTE:polled rather than blocking, so that a caller can stop waiting to send a signal
+        loop {
+            if let Some(code) = self.try_wait().await? {
+                return Ok(code);
+            }
+            if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                unsafe { TerminateProcess(self.handle, 1) }?;
+                Err(timed_out())?
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    async fn try_wait(&mut self) -> Result<Option<i32>> {
+        if unsafe { WaitForSingleObject(self.handle, 0) } == WAIT_TIMEOUT {
+            return Ok(None);
+        }
         let mut exit_code: u32 = 0;
-        unsafe { WaitForSingleObject(self.handle, INFINITE) };
         unsafe { GetExitCodeProcess(self.handle, &mut exit_code as *mut u32) }?;
         debug!("exit code: {}", exit_code);
-        Ok(exit_code as i32)
+        Ok(Some(exit_code as i32))
+    }
+    async fn signal(&mut self, sig: Signal) -> Result<()> {
+        match sig {
+            //NOTE:there are no signals, both end the process like `taskkill /F` does
+            Signal::Kill | Signal::Term => {
+                unsafe { TerminateProcess(self.handle, 128 + sig.number() as u32) }?;
+                Ok(())
+            }
+            Signal::Hup | Signal::Int => Err(unsupported(&format!("signal {}", sig)))?,
+        }
     }
 }
 
@@ -199,7 +232,36 @@ impl Drop for SafeHandle {
     }
 }
 
-pub fn openpty(window_size: WindowSize, command: Script<'_, '_>) -> std::io::Result<BoxedPty> {
+/// The environment block of a process, `env` over `vars`. The names are case-insensitive,
+/// so they are sorted and deduplicated by their uppercase form as Windows expects.
+fn env_block(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+    env: &HashMap<String, String>,
+) -> Vec<u16> {
+    let mut folded = BTreeMap::new();
+    for (key, value) in vars
+        .into_iter()
+        .chain(env.iter().map(|(k, v)| (k.into(), v.into())))
+    {
+        //NOTE:keys may not be unicode, they are kept as is and only folded lossily
+        folded.insert(key.to_string_lossy().to_uppercase(), (key, value));
+    }
+    let mut block = Vec::new();
+    for (key, value) in folded.into_values() {
+        block.extend(key.encode_wide());
+        block.push(u16::from(b'='));
+        block.extend(value.encode_wide());
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
+pub fn openpty(
+    window_size: WindowSize,
+    command: Script<'_, '_>,
+    opts: &ExecOptions,
+) -> std::io::Result<BoxedPty> {
     let api = ConptyApi::new();
 
     let mut conout = SafeHandle::default();
@@ -278,39 +340,55 @@ pub fn openpty(window_size: WindowSize, command: Script<'_, '_>) -> std::io::Res
         }
         Ok(program)
     };
+    //NOTE:kept until the process exits, Windows refuses to remove a file in use
+    let mut script = None;
     let mut cmdline = match command {
-        Script::Whole(cmd) => win32_string(cmd),
+        Script::Whole(cmd) => {
+            let mut program = abs_path("powershell")?;
+            program.extend(" -NoProfile -Command ".encode_utf16());
+            program.extend(quote::windows_arg(cmd).encode_utf16());
+            program.push(0);
+            program
+        }
         Script::Split { program, args } => {
             let mut program = abs_path(program)?;
             for arg in args {
                 program.push(' ' as u16);
-                program.extend(arg.encode_utf16());
+                program.extend(quote::windows_arg(arg).encode_utf16());
             }
             program.push(0);
             program
         }
         Script::Script { executor, input } => {
             let mut program = abs_path(executor.as_ref())?;
-            let mut tmp = tempfile::NamedTempFile::with_suffix(".ps1")?;
+            let mut tmp = tempfile::NamedTempFile::with_suffix(executor.suffix())?;
             for line in input {
                 tmp.write_all(line.as_bytes())?;
             }
-            tmp.write_all(
-                "\r\nRemove-Item $MyInvocation.MyCommand.Path\r\n"
-                    .to_string()
-                    .as_bytes(),
-            )?;
-
-            let path = tmp.into_temp_path().keep()?;
-            program.extend(" -f ".encode_utf16());
-            program.extend(path.as_os_str().encode_wide());
+
+            let path = tmp.into_temp_path();
+            if executor == ScriptExecutor::Powershell {
+                program.extend(" -f".encode_utf16());
+            }
+            program.push(' ' as u16);
+            program.extend(quote::windows_arg(&path.to_string_lossy()).encode_utf16());
             program.push(0);
             debug!("command line: {}", String::from_utf16_lossy(&program));
             debug!("script content: {}", std::fs::read_to_string(&path)?);
+            script = Some(path);
             program
         }
     };
-    let creation_flags = EXTENDED_STARTUPINFO_PRESENT;
+    let cwd = opts
+        .cwd
+        .as_ref()
+        .map(|cwd| cwd.encode_utf16().chain(once(0)).collect::<Vec<u16>>());
+    //NOTE:the block replaces the whole environment, so start from the current one
+    let env = (!opts.env.is_empty()).then(|| env_block(std::env::vars_os(), &opts.env));
+    let mut creation_flags = EXTENDED_STARTUPINFO_PRESENT;
+    if env.is_some() {
+        creation_flags |= CREATE_UNICODE_ENVIRONMENT;
+    }
 
     let mut proc_info = PROCESS_INFORMATION::default();
 
@@ -322,8 +400,10 @@ pub fn openpty(window_size: WindowSize, command: Script<'_, '_>) -> std::io::Res
             None,
             false,
             creation_flags,
-            None,
-            None,
+            env.as_ref()
+                .map(|env| env.as_ptr() as *const std::ffi::c_void),
+            cwd.as_ref()
+                .map_or(PCWSTR::null(), |cwd| PCWSTR::from_raw(cwd.as_ptr())),
             &mut startup_info_ex.StartupInfo as *mut STARTUPINFOW,
             &mut proc_info as *mut PROCESS_INFORMATION,
         )
@@ -333,12 +413,13 @@ pub fn openpty(window_size: WindowSize, command: Script<'_, '_>) -> std::io::Res
         DeleteProcThreadAttributeList(startup_info_ex.lpAttributeList);
     }
     let prevent_deadlock = Arc::new(AtomicBool::new(false));
-    Ok(BoxedPty::new(
+    let pty = BoxedPty::new(
         PtyCtlImpl {
             prevent_deadlock: prevent_deadlock.clone(),
             hpcon: pty_handle,
             handle: proc_info.hProcess,
             close: api.close,
+            deadline: opts.timeout.map(|timeout| Instant::now() + timeout),
         },
         PtyWriterImpl {
             prevent_deadlock: prevent_deadlock.clone(),
@@ -350,12 +431,41 @@ pub fn openpty(window_size: WindowSize, command: Script<'_, '_>) -> std::io::Res
             prevent_deadlock,
             out: conout,
         },
-    ))
+    );
+    Ok(match script {
+        Some(script) => pty.with_guard(script),
+        None => pty,
+    })
 }
 
-pub fn win32_string<S: AsRef<OsStr> + ?Sized>(value: &S) -> Vec<u16> {
-    OsStr::new(value)
-        .encode_wide()
-        .chain(std::iter::once(0))
-        .collect()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_block_folds_case() {
+        let vars = [("Path", "C:\\a"), ("b", "1"), ("windir", "C:\\Windows")]
+            .map(|(k, v)| (OsString::from(k), OsString::from(v)));
+        let env = HashMap::from([("PATH".to_string(), "C:\\b".to_string())]);
+        let block = String::from_utf16(&env_block(vars, &env)).unwrap();
+        assert_eq!(block, "b=1\0PATH=C:\\b\0windir=C:\\Windows\0\0");
+    }
+
+    #[test]
+    fn env_block_keeps_non_unicode() {
+        use std::os::windows::ffi::OsStringExt;
+        let lone = OsString::from_wide(&[u16::from(b'x'), 0xD800]);
+        let block = env_block([("X".into(), lone)], &HashMap::new());
+        assert_eq!(
+            block,
+            [
+                u16::from(b'X'),
+                u16::from(b'='),
+                u16::from(b'x'),
+                0xD800,
+                0,
+                0
+            ]
+        );
+    }
 }
@@ -6,4 +6,5 @@ pub mod prelude {
 mod core;
 mod error;
 mod instance;
+pub mod quote;
 pub use error::{Error, ErrorChain, Result};
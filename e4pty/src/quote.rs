@@ -0,0 +1,161 @@
+//! Quoting of arguments, so that a shell or a Windows command line reads each one back
+//! as a single literal word.
+
+use std::{borrow::Cow, iter::repeat_n};
+
+fn is_safe(word: &str, allowed: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || allowed.contains(c))
+}
+
+/// Quote a word for POSIX sh, single quotes keep everything but `'` literal.
+pub fn sh(word: &str) -> Cow<'_, str> {
+    //NOTE:`=` is left out, a leading `a=b` would be taken as an assignment
+    if is_safe(word, "_-./:,+@%") {
+        return word.into();
+    }
+    format!("'{}'", word.replace('\'', r"'\''")).into()
+}
+
+/// Quote a word for fish, whose single quotes still take `\\` and `\'` as escapes.
+pub fn fish(word: &str) -> Cow<'_, str> {
+    if is_safe(word, "_-./:,+@%") {
+        return word.into();
+    }
+    format!("'{}'", word.replace('\\', r"\\").replace('\'', r"\'")).into()
+}
+
+/// Quote a word for PowerShell, single quotes keep everything literal except themselves,
+/// which includes the typographic ones PowerShell accepts as well.
+pub fn powershell(word: &str) -> Cow<'_, str> {
+    //NOTE:`,` builds an array, a leading `-` or `@` is a parameter or a splat
+    if is_safe(word, "_-./:\\") && !word.starts_with(['-', '@']) {
+        return word.into();
+    }
+    let mut quoted = String::with_capacity(word.len() + 2);
+    quoted.push('\'');
+    for c in word.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}') {
+            quoted.push(c);
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted.into()
+}
+
+/// Push `arg` with its `"` escaped for `CommandLineToArgvW`, the backslashes before them are
+/// doubled. Returns the number of trailing backslashes, which are left to the caller.
+fn escape_quotes(arg: &str, out: &mut String) -> usize {
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        if c == '\\' {
+            backslashes += 1;
+            continue;
+        }
+        let escapes = if c == '"' {
+            backslashes * 2 + 1
+        } else {
+            backslashes
+        };
+        out.extend(repeat_n('\\', escapes));
+        out.push(c);
+        backslashes = 0;
+    }
+    backslashes
+}
+
+/// Quote an argument of a native program for Windows PowerShell.
+/// PowerShell 5.1 drops the embedded `"` when it builds the command line of a native program,
+/// so they are escaped as `CommandLineToArgvW` expects first. Cmdlets must get `powershell`.
+pub fn powershell_native(arg: &str) -> Cow<'_, str> {
+    let spaced = arg.contains(char::is_whitespace);
+    if !arg.contains('"') && !(spaced && arg.ends_with('\\')) {
+        return powershell(arg);
+    }
+    let mut escaped = String::with_capacity(arg.len() + 2);
+    let backslashes = escape_quotes(arg, &mut escaped);
+    //NOTE:PowerShell wraps an argument with spaces in quotes, which must not be escaped
+    let trailing = match spaced {
+        true => backslashes * 2,
+        false => backslashes,
+    };
+    escaped.extend(repeat_n('\\', trailing));
+    powershell(&escaped).into_owned().into()
+}
+
+/// Quote an argument of a Windows command line, following the rules of `CommandLineToArgvW`.
+pub fn windows_arg(arg: &str) -> Cow<'_, str> {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '\n', '\x0b', '"']) {
+        return arg.into();
+    }
+    let mut quoted = String::with_capacity(arg.len() + 2);
+    quoted.push('"');
+    let backslashes = escape_quotes(arg, &mut quoted);
+    //NOTE:the closing quote must not be escaped
+    quoted.extend(repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+    quoted.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sh_words() {
+        assert_eq!(sh("ls"), "ls");
+        assert_eq!(sh("/tmp/a-b_c.txt"), "/tmp/a-b_c.txt");
+        assert_eq!(sh(""), "''");
+        assert_eq!(sh("a b"), "'a b'");
+        assert_eq!(sh("it's"), r"'it'\''s'");
+        assert_eq!(sh("$(rm -rf ~)"), "'$(rm -rf ~)'");
+        assert_eq!(sh("`id`;&|<>*?\n"), "'`id`;&|<>*?\n'");
+        assert_eq!(sh("'"), r"''\'''");
+    }
+
+    #[test]
+    fn fish_words() {
+        assert_eq!(fish("ls"), "ls");
+        assert_eq!(fish("a b"), "'a b'");
+        assert_eq!(fish("it's"), r"'it\'s'");
+        assert_eq!(fish(r"C:\dir"), r"'C:\\dir'");
+        assert_eq!(fish("(rm -rf ~)"), "'(rm -rf ~)'");
+    }
+
+    #[test]
+    fn powershell_words() {
+        assert_eq!(powershell(r"C:\Users\me"), r"C:\Users\me");
+        assert_eq!(powershell(""), "''");
+        assert_eq!(powershell("a b"), "'a b'");
+        assert_eq!(powershell("it's"), "'it''s'");
+        assert_eq!(powershell("\u{2019}; rm"), "'\u{2019}\u{2019}; rm'");
+        assert_eq!(powershell("$env:PATH"), "'$env:PATH'");
+        assert_eq!(powershell("-Force"), "'-Force'");
+        assert_eq!(powershell("@(1)"), "'@(1)'");
+    }
+
+    #[test]
+    fn powershell_native_args() {
+        assert_eq!(powershell_native("a b"), "'a b'");
+        assert_eq!(powershell_native(r#"say "hi""#), r#"'say \"hi\"'"#);
+        assert_eq!(powershell_native(r#"{"k":1}"#), r#"'{\"k\":1}'"#);
+        assert_eq!(powershell_native(r#"a\"b"#), r#"'a\\\"b'"#);
+        assert_eq!(powershell_native(r#"it's "x" \"#), r#"'it''s \"x\" \\'"#);
+        assert_eq!(powershell_native(r"C:\dir\"), r"C:\dir\");
+        assert_eq!(powershell_native(r"C:\my dir\"), r"'C:\my dir\\'");
+    }
+
+    #[test]
+    fn windows_args() {
+        assert_eq!(windows_arg("plain"), "plain");
+        assert_eq!(windows_arg(""), "\"\"");
+        assert_eq!(windows_arg("a b"), "\"a b\"");
+        assert_eq!(windows_arg(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(windows_arg(r"C:\dir\"), r"C:\dir\");
+        assert_eq!(windows_arg(r"C:\my dir\"), r#""C:\my dir\\""#);
+        assert_eq!(windows_arg(r#"a\"b"#), r#""a\\\"b""#);
+    }
+}